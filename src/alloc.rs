@@ -1,244 +1,411 @@
 use std::ffi::c_void;
-use std::ptr::{null_mut, read_volatile};
+use std::ptr::null_mut;
 
-use proc_getter::buddyinfo::buddyinfo;
-use vm_info::page_map::read_page_map;
 use vm_info::page_size;
-use vm_info::ProcessId::SelfPid;
 
 use crate::architecture::PhysAddr;
 use crate::config::Config;
+use crate::exhaust::BuddyExhaustion;
+use crate::hugepages::{HugePages, PageSize};
 use crate::memmap::MemMap;
-use std::ptr;
+use crate::numa::bound_node;
+use crate::phys::{HugePageRelative, Pagemap, PhysResolver, RunRelative, TimingAlignment};
+use crate::spoiler::Spoiler;
+use crate::thp::Thp;
 use nix::libc;
-use std::collections::{HashSet, HashMap};
-use std::time::Instant;
-use crate::hammer::hammer;
-use nix::sys::socket::bind;
-
-const SIZE_MB: usize = 1 << 20;
+use std::fmt;
+use std::io;
 
 const HUGE_PAGE_BITS: usize = 21;
 const HUGE_PAGE_SIZE: usize = 1 << HUGE_PAGE_BITS;
 const MAP_HUGE_2MB: i32 = 21 << 26; // 21 << 26
 const MAP_HUGE_1GB: i32 = 30 << 26; // 30 << 26
+const GIGA_PAGE_BITS: usize = 30;
+const GIGA_PAGE_SIZE: usize = 1 << GIGA_PAGE_BITS;
 
-fn sum_frees() -> usize {
-    let bis = buddyinfo().unwrap();
-    let mut sum: usize = 0;
+fn hugepage_pool(page_kb: usize) -> String {
+    format!("/sys/kernel/mm/hugepages/hugepages-{}kB", page_kb)
+}
 
-    for (i, num) in bis[1].page_nums().iter().enumerate() {
-        //println!("There are {} free things in slot {}", num, i);
-        sum += num.clone() << i;
+// free huge pages of the given size, None if no node has a pool of that size
+// while bound to a node only its own pool can serve the allocation
+fn free_hugepages(pools: &HugePages, size: PageSize) -> Option<usize> {
+    if let Some(node) = bound_node() {
+        return Some(pools.pool(node, size)?.free);
     }
-    for (i, num) in bis[2].page_nums().iter().enumerate() {
-        //println!("There are {} free things in slot {}", num, i);
-        sum += num.clone() << i;
+    let free: Vec<usize> = pools.nodes().into_iter().filter_map(|n| Some(pools.pool(n, size)?.free)).collect();
+    if free.is_empty() {
+        return None;
+    }
+    Some(free.iter().sum())
+}
+
+#[derive(Debug)]
+pub(crate) enum AllocError {
+    // mmap failed with errno
+    MapFailed(i32),
+    // pagemap shows a physical discontinuity at this offset
+    NotContiguous { offset: usize },
+    // pagemap has no frame numbers, usually because we are not root
+    NoFrameNumbers,
+    // the kernel did not back the mapping with a transparent huge page
+    NoHugePage,
+    // no free huge pages of this size (in kB) in the pool
+    PoolExhausted { page_kb: usize },
+    // physically contiguous, but not aligned to the page size
+    Misaligned { phys: PhysAddr },
+    // the buddy allocator state could not be read or has no block of the needed order
+    Buddy(String),
+    // draining the buddy allocator stopped early to stay clear of reclaim and the OOM killer
+    MemoryPressure { drained: usize },
+    // the memory policy could not be set
+    Numa(String),
+    // bound to one node, but the memory was allocated on another
+    WrongNode { wanted: usize, node: usize },
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AllocError::MapFailed(errno) => {
+                write!(f, "mmap failed: {}", io::Error::from_raw_os_error(*errno))
+            }
+            AllocError::NotContiguous { offset } => {
+                write!(f, "memory is not physically contiguous at offset {:#x}", offset)
+            }
+            AllocError::NoFrameNumbers => write!(f, "pagemap shows no frame numbers, run as root"),
+            AllocError::NoHugePage => write!(f, "mapping is not backed by a huge page"),
+            AllocError::PoolExhausted { page_kb } => {
+                write!(f, "no free {} kB huge pages, reserve some in {}", page_kb, hugepage_pool(*page_kb))
+            }
+            AllocError::Misaligned { phys } => write!(f, "physical address {:#x} is misaligned", phys),
+            AllocError::Buddy(e) => write!(f, "buddy allocator: {}", e),
+            AllocError::MemoryPressure { drained } => {
+                write!(f, "stopped after draining {} bytes to avoid memory pressure", drained)
+            }
+            AllocError::Numa(e) => write!(f, "NUMA policy: {}", e),
+            AllocError::WrongNode { wanted, node } => {
+                write!(f, "memory was allocated on node {} instead of node {}", node, wanted)
+            }
+        }
     }
-    sum * page_size().unwrap_or(4096)
 }
 
-fn map_eager(sz: usize) -> Option<(*mut u8, usize)> {
+// what is known about the physical layout of an allocation
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Contiguity {
+    // pagemap confirmed the whole buffer is contiguous, starting at phys
+    Verified { phys: PhysAddr },
+    // a single huge page of 1 << bits bytes, contiguous by construction
+    HugePage { bits: usize },
+    // row conflicts measured in the buffer agree with the address mapping for this fraction of pairs
+    Timed { agreement: f64 },
+    // SPOILER found a run of this many MB, aligned relative to other runs of the same trace
+    Aliasing { mbs: usize },
+    // not contiguous, pagemap translates every page
+    Scattered,
+}
+
+impl Contiguity {
+    // what the allocation tells about the physical addresses of buf
+    pub(crate) fn resolver(&self, buf: *const u8) -> Box<dyn PhysResolver> {
+        match *self {
+            Contiguity::Verified { .. } | Contiguity::Scattered => Box::new(Pagemap),
+            Contiguity::HugePage { bits } => Box::new(HugePageRelative { bits }),
+            // buddy blocks of 2 MB are aligned to 2 MB
+            Contiguity::Timed { .. } => Box::new(TimingAlignment { base: buf, offset: 0, bits: HUGE_PAGE_BITS }),
+            // the offset of a run to the 1 MB boundary is unknown, runs are only aligned to each other
+            Contiguity::Aliasing { .. } => Box::new(RunRelative { base: buf }),
+        }
+    }
+}
+
+pub(crate) struct Allocation {
+    pub mem: MemMap,
+    pub contiguity: Contiguity,
+}
+
+pub(crate) trait AllocationStrategy {
+    fn name(&self) -> &'static str;
+    fn allocate(&self, c: &Config) -> Result<Allocation, AllocError>;
+}
+
+pub(crate) fn map(sz: usize, flags: i32) -> Result<*mut u8, AllocError> {
     let mem: *mut c_void = unsafe {
         libc::mmap(
             null_mut(),
             sz,
             libc::PROT_READ | libc::PROT_WRITE,
-            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_POPULATE,
+            flags,
             -1,
             0,
         )
     };
-    Some((mem as *mut u8, sz))
+    if mem == libc::MAP_FAILED {
+        return Err(AllocError::MapFailed(io::Error::last_os_error().raw_os_error().unwrap_or(0)));
+    }
+    Ok(mem as *mut u8)
+}
+
+// map twice len and trim it to a region aligned to len, a power of two
+pub(crate) fn map_aligned(len: usize, flags: i32) -> Result<*mut u8, AllocError> {
+    let raw = map(2 * len, flags)?;
+    let buf = ((raw as usize + len - 1) & !(len - 1)) as *mut u8;
+    let head = buf as usize - raw as usize;
+    if head > 0 {
+        unmap(raw, head);
+    }
+    unmap(buf.wrapping_add(len), len - head);
+    Ok(buf)
 }
 
-fn rdtsc() -> u64 {
-    let mut a = 0;
-    let mut d = 0;
+pub(crate) fn unmap(buf: *mut u8, sz: usize) {
     unsafe {
-        asm!("xor %rax, %rax" ::: "rax" : "intel", "volatile");
-        asm!("cpuid" ::: "rax", "rbx", "rcx", "rdx": "intel", "volatile");
-        asm!("rdtsc" : "=rw" (a), "=rw" (d) ::: "intel", "volatile");
+        libc::munmap(buf as *mut _, sz);
     }
-    (d << 32) | a
 }
 
-fn get_timing(a1 : *const u8, a2 : *const u8, num_reads : usize) -> usize {
-    for _ in 0..10 {
-        unsafe {libc::sched_yield()};
+pub(crate) fn map_eager(sz: usize) -> Result<(*mut u8, usize), AllocError> {
+    let mem = map(sz, libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_POPULATE)?;
+    Ok((mem, sz))
+}
+
+// check with pagemap that every page of buf follows the previous one physically
+pub(crate) fn verify_contiguity(buf: *const u8, len: usize) -> Result<Contiguity, AllocError> {
+    verify_contiguity_stride(buf, len, page_size().unwrap_or(4096))
+}
+
+// like verify_contiguity, but only checks every stride bytes and the last byte
+pub(crate) fn verify_contiguity_stride(buf: *const u8, len: usize, stride: usize) -> Result<Contiguity, AllocError> {
+    let start = virt_to_phys_pagemap(buf).ok_or(AllocError::NoFrameNumbers)?;
+
+    for offset in (stride..len).step_by(stride).chain(std::iter::once(len - 1)) {
+        match virt_to_phys_pagemap(buf.wrapping_add(offset)) {
+            Some(p) if p == start + offset => {}
+            _ => return Err(AllocError::NotContiguous { offset }),
+        }
     }
+    Ok(Contiguity::Verified { phys: start })
+}
 
-    let start = rdtsc();
-    println!("start{}", start);
-    unsafe {
-        for _ in 0..num_reads {
-            asm!("clflush [$0]\n\t\
-                  clflush [$1]"
-                  :
-                  : "r"(a1), "r"(a2)
-                  :
-                  : "volatile", "memory", "intel");
-
-            read_volatile(a1);
-            read_volatile(a2);
+// huge pages are contiguous by construction, pagemap only adds the physical address
+pub(crate) fn huge_page_contiguity(buf: *const u8, len: usize, bits: usize) -> Result<Contiguity, AllocError> {
+    match verify_contiguity(buf, len) {
+        Err(AllocError::NoFrameNumbers) => Ok(Contiguity::HugePage { bits }),
+        r => r,
+    }
+}
+
+pub(crate) fn checked(buf: *mut u8, len: usize, contiguity: Result<Contiguity, AllocError>, c: &Config) -> Result<Allocation, AllocError> {
+    checked_pages(buf, len, page_size().unwrap_or(4096), contiguity, c)
+}
+
+// like checked, for a mapping that is physically contiguous within every page of page_size
+fn checked_pages(
+    buf: *mut u8,
+    len: usize,
+    page_size: usize,
+    contiguity: Result<Contiguity, AllocError>,
+    c: &Config,
+) -> Result<Allocation, AllocError> {
+    match contiguity {
+        Ok(contiguity) => Ok(Allocation {
+            mem: unsafe { MemMap::with_page_size(buf, len, page_size, &*contiguity.resolver(buf), c) },
+            contiguity,
+        }),
+        Err(e) => {
+            unmap(buf, len);
+            Err(e)
         }
     }
+}
 
-    let end = rdtsc();
-    println!("end{}", end);
+pub(crate) struct Hugetlb2mb;
+// checks the pools below pools, so a fake sysfs root can stand in for the real one
+#[derive(Default)]
+pub(crate) struct Hugetlb1gb {
+    pub pools: HugePages,
+}
 
-    ((end - start) / num_reads as u64) as usize
+// plain 4 KB pages, needs pagemap to place them in DRAM
+pub(crate) struct Pages4k {
+    pub bytes: usize,
 }
 
+pub(crate) fn virt_to_phys_pagemap(v: *const u8) -> Option<PhysAddr> {
+    Pagemap.resolve(v)
+}
 
-pub(crate) fn reverse_mapping(c : &Config, buf : *mut u8) -> usize {
-    let arch_map = create_offset_map(c);
-    let reads_per_it = 10000000;
-    let mut page_off_cand = HashSet::new();
-    for i in 0..512 {
-        page_off_cand.insert(i);
+impl AllocationStrategy for Hugetlb1gb {
+    fn name(&self) -> &'static str {
+        "hugetlb 1 GB"
     }
 
-    println!("dram_to_phys: {:X?}", virt_to_phys_pagemap(buf));
-    let t0 = Instant::now();
-    hammer(buf, buf.wrapping_add(4096), reads_per_it);
-    let blind2 = get_timing(buf, buf.wrapping_add(0), reads_per_it);
-    let blind = t0.elapsed().as_nanos();
-    println!("blind value: {}", blind);
-    println!("blind rdtsc: {}", blind2);
-
-        for i in 1..0 {
-            if page_off_cand.len() <= 1 {
-                break;
-            }
+    //setup: as root do: echo 1 > /sys/kernel/mm/hugepages/hugepages-1048576kB/nr_hugepages
+    fn allocate(&self, c: &Config) -> Result<Allocation, AllocError> {
+        match free_hugepages(&self.pools, PageSize::Gb1) {
+            Some(n) if n > 0 => {}
+            _ => return Err(AllocError::PoolExhausted { page_kb: PageSize::Gb1.kb() }),
+        }
 
-            for j in 1..*arch_map.keys().max().unwrap_or(&0) {
-                println!("{:#?}", c.arch.phys_to_dram(4096*(i+j)));
-                let t0 = Instant::now();
-                hammer(buf.wrapping_add(4096*i), buf.wrapping_add(4096 * (i+j)), reads_per_it);
-                let t_diff = t0.elapsed().as_nanos();
-                println!("{}", t_diff);
-                if t_diff >= (blind * 5) / 3 {
-                    page_off_cand.intersection(
-                        &arch_map
-                            .get(&j)
-                            .unwrap_or(&HashSet::new())
-                            .iter()
-                            .map(|p| p+j)
-                            .collect::<HashSet<usize>>());
-                    println!("found offset: {} to be slow",  j);
-                    println!("new cands: {:#?}", page_off_cand);
-                    break;
+        let buf = map(
+            GIGA_PAGE_SIZE,
+            libc::MAP_SHARED
+                | libc::MAP_ANONYMOUS
+                | libc::MAP_HUGETLB
+                | libc::MAP_POPULATE
+                | MAP_HUGE_1GB,
+        )?;
 
-                }
+        // a 1 GB page is contiguous within itself, checking every 2 MB keeps pagemap reads low
+        let contiguity = match verify_contiguity_stride(buf, GIGA_PAGE_SIZE, HUGE_PAGE_SIZE) {
+            Ok(Contiguity::Verified { phys }) if phys % GIGA_PAGE_SIZE != 0 => {
+                Err(AllocError::Misaligned { phys })
             }
+            Err(AllocError::NoFrameNumbers) => Ok(Contiguity::HugePage { bits: GIGA_PAGE_BITS }),
+            r => r,
+        };
+        // translated and indexed as one page, rows are looked up in the index without a
+        // pagemap read or a frame entry per 4 KB
+        checked_pages(buf, GIGA_PAGE_SIZE, GIGA_PAGE_SIZE, contiguity, c)
     }
-
-    *page_off_cand.iter().collect::<Vec<&usize>>()[0]
 }
 
-fn create_offset_map(c : &Config) -> HashMap<usize, HashSet<usize>> {
-    let mut m = HashMap::new();
+impl AllocationStrategy for Hugetlb2mb {
+    fn name(&self) -> &'static str {
+        "hugetlb 2 MB"
+    }
 
-    for i in 0..512 {
-        let off = i * page_size().unwrap_or(4096);
-        let da_off= c.arch.phys_to_dram(off);
-        for j in 1..512 {
-            let cur = off + j * page_size().unwrap_or(4096);
-            let da_cur = c.arch.phys_to_dram(cur);
-            if da_cur.chan == da_off.chan
-                && da_cur.dimm == da_off.dimm
-                && da_cur.bank == da_off.bank {
-                m.entry(j)
-                    .or_insert_with(HashSet::new)
-                    .insert(i);
-                break;
-            }
+    //setup: as root do: echo 512 > /sys/devices/system/node/node0/hugepages/hugepages-2048kB/nr_hugepages
+    fn allocate(&self, c: &Config) -> Result<Allocation, AllocError> {
+        // mmap only checks the global pool, an empty pool on the bound node fails at the first touch
+        if bound_node().is_some() && free_hugepages(&HugePages::default(), PageSize::Mb2).unwrap_or(0) == 0 {
+            return Err(AllocError::PoolExhausted { page_kb: PageSize::Mb2.kb() });
         }
+
+        let buf = map(
+            HUGE_PAGE_SIZE,
+            libc::MAP_SHARED
+                | libc::MAP_ANONYMOUS
+                | libc::MAP_HUGETLB
+                | libc::MAP_POPULATE
+                | MAP_HUGE_2MB,
+        )?;
+        checked(buf, HUGE_PAGE_SIZE, huge_page_contiguity(buf, HUGE_PAGE_SIZE, HUGE_PAGE_BITS), c)
     }
-    m
 }
 
-pub(crate) fn alloc_2mb_buddy(c: &Config) -> Option<MemMap> {
-    let alloc_sz = sum_frees() - SIZE_MB;
-
-    println!("Bytes in Buddy-allocator {}\n", alloc_sz);
-    let mem_buddy_rest = map_eager(alloc_sz)?;
+// tries each strategy in order and returns the first allocation that succeeds
+pub(crate) struct Fallback(pub Vec<Box<dyn AllocationStrategy>>);
 
-    println!("Bytes in Buddy-allocator {}\n", sum_frees());
-    let mem_buddy_rest_2mb = map_eager(2 * SIZE_MB)?;
-    println!("Bytes in Buddy-allocator {}\n", sum_frees());
-    let mem_attack = map_eager(2 * SIZE_MB)?;
+impl AllocationStrategy for Fallback {
+    fn name(&self) -> &'static str {
+        "fallback"
+    }
 
-    unsafe {
-        libc::munmap(mem_buddy_rest.0 as *mut _, alloc_sz);
-        libc::munmap(mem_buddy_rest_2mb.0 as *mut _, 2 * SIZE_MB);
+    fn allocate(&self, c: &Config) -> Result<Allocation, AllocError> {
+        let mut last = AllocError::NoHugePage;
+        for s in &self.0 {
+            match s.allocate(c) {
+                Ok(a) => {
+                    println!("Allocated with {}", s.name());
+                    return Ok(a);
+                }
+                Err(e) => {
+                    println!("{} allocation failed: {}", s.name(), e);
+                    last = e;
+                }
+            }
+        }
+        Err(last)
     }
-    Some(MemMap::new(mem_attack.0, mem_attack.1, &c))
 }
 
-pub(crate) fn virt_to_phys_pagemap(v: *const u8) -> Option<PhysAddr> {
-    let v = v as usize;
-    let page_size = page_size().unwrap_or(4096);
-    let page_num = v / page_size;
+impl AllocationStrategy for Pages4k {
+    fn name(&self) -> &'static str {
+        "4 KB pages"
+    }
 
-    let vpage = read_page_map(SelfPid, page_num).ok()?;
-    let frame = vpage.page_frame()?;
-    Some(frame as usize * page_size + v % page_size)
+    fn allocate(&self, c: &Config) -> Result<Allocation, AllocError> {
+        let (buf, len) = map_eager(self.bytes)?;
+        if virt_to_phys_pagemap(buf).is_none() {
+            unmap(buf, len);
+            return Err(AllocError::NoFrameNumbers);
+        }
+        checked(buf, len, Ok(Contiguity::Scattered), c)
+    }
 }
 
-//setup: as root do: echo 512 > /sys/devices/system/node/node0/hugepages/hugepages-2048kB/free_hugepages
-pub(crate) fn alloc_1gb_hugepage(c: &Config) -> Option<MemMap> {
-    let mem_attack = unsafe {
-        libc::mmap(
-            ptr::null_mut(),
-            HUGE_PAGE_SIZE << 9,
-            libc::PROT_READ | libc::PROT_WRITE,
-            libc::MAP_SHARED | libc::MAP_ANONYMOUS | libc::MAP_POPULATE | MAP_HUGE_1GB,
-            -1,
-            0,
-        )
+// names of the strategies selectable on the command line, auto is the default
+pub(crate) const STRATEGY_NAMES: [&str; 6] = ["auto", "hugetlb-2m", "hugetlb-1g", "thp", "spoiler", "buddy"];
+
+// auto tries a 2 MB hugetlb page, then a transparent huge page, then SPOILER
+pub(crate) fn strategy_by_name(name: &str) -> Option<Box<dyn AllocationStrategy>> {
+    let s: Box<dyn AllocationStrategy> = match name {
+        "auto" => Box::new(Fallback(vec![
+            Box::new(Hugetlb2mb),
+            Box::new(Thp::default()),
+            Box::new(Spoiler::default()),
+        ])),
+        "hugetlb-2m" => Box::new(Hugetlb2mb),
+        "hugetlb-1g" => Box::new(Hugetlb1gb::default()),
+        "thp" => Box::new(Thp::default()),
+        "spoiler" => Box::new(Spoiler::default()),
+        "buddy" => Box::new(BuddyExhaustion::default()),
+        _ => return None,
     };
+    Some(s)
+}
 
-    Some(MemMap::new(mem_attack as *mut u8, HUGE_PAGE_SIZE << 9, c))
+// size of the huge pages a strategy takes from the hugetlb pools
+pub(crate) fn strategy_page_size(name: &str) -> PageSize {
+    match name {
+        "hugetlb-1g" => PageSize::Gb1,
+        _ => PageSize::Mb2,
+    }
 }
 
 pub(crate) fn alloc_2mb_hugepage(c: &Config) -> Option<MemMap> {
-    let mem_attack = unsafe {
-        libc::mmap(
-            ptr::null_mut(),
-            HUGE_PAGE_SIZE,
-            libc::PROT_READ | libc::PROT_WRITE,
-            libc::MAP_SHARED
-                | libc::MAP_ANONYMOUS
-                | libc::MAP_HUGETLB
-                | libc::MAP_POPULATE
-                | MAP_HUGE_2MB,
-            -1,
-            0,
-        )
-    };
+    Hugetlb2mb.allocate(c).ok().map(|a| a.mem)
+}
 
-    Some(MemMap::new(mem_attack as *mut u8, HUGE_PAGE_SIZE, c))
-}
-
-//fn vpage_to_page(virtual_page_num: usize) -> Option<usize> {
-//    let path = String::from("/proc/self/pagemap");
-//
-//    let mut f = fs::File::open(path).ok()?;
-//    // Each entry is 8 bytes wide
-//    let offset = virtual_page_num as u64 * 8;
-//    f.seek(io::SeekFrom::Start(offset)).ok()?;
-//
-//    let data = f.read_u64::<byteorder::NativeEndian>().ok()?;
-//
-//    Some(data as usize)
-//}
-
-//Test
-pub fn contig_mem_diff(c: &Config) {
-    let mem_attack = alloc_2mb_buddy(c).unwrap();
-    let start_p = virt_to_phys_pagemap(&mem_attack[0]).unwrap();
-    let end_p = virt_to_phys_pagemap(&mem_attack[2 * SIZE_MB - 1]).unwrap();
-    assert_eq!(start_p + 2 * SIZE_MB - 1, end_p)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::config;
+    use std::fs;
+
+    #[test]
+    fn giga_pool_is_read_below_root() {
+        let root = std::env::temp_dir().join(format!("rambleed-alloc-{}", std::process::id()));
+        for (node, free) in &[(0, 0), (1, 2)] {
+            let dir = root.join(format!("node{}/hugepages/hugepages-1048576kB", node));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("nr_hugepages"), "2\n").unwrap();
+            fs::write(dir.join("free_hugepages"), format!("{}\n", free)).unwrap();
+        }
+        let pools = HugePages::new(&root);
+        assert_eq!(free_hugepages(&pools, PageSize::Gb1), Some(2));
+        assert_eq!(free_hugepages(&pools, PageSize::Mb2), None);
+
+        fs::write(root.join("node1/hugepages/hugepages-1048576kB/free_hugepages"), "0\n").unwrap();
+        match (Hugetlb1gb { pools }).allocate(&config(64_000)) {
+            Err(AllocError::PoolExhausted { page_kb }) => assert_eq!(page_kb, 1048576),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("allocated from an empty pool"),
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn strategies_by_name() {
+        for name in STRATEGY_NAMES.iter() {
+            assert!(strategy_by_name(name).is_some(), "{}", name);
+        }
+        assert_eq!(strategy_by_name("hugetlb-1g").unwrap().name(), "hugetlb 1 GB");
+        assert_eq!(strategy_by_name("hugetlb-2m").unwrap().name(), "hugetlb 2 MB");
+        assert!(strategy_by_name("hugetlb-4m").is_none());
+        assert_eq!(strategy_page_size("hugetlb-1g"), PageSize::Gb1);
+        assert_eq!(strategy_page_size("auto"), PageSize::Mb2);
+    }
 }
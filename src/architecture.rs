@@ -12,6 +12,29 @@ pub struct DramAddr {
     pub bit: u8,
 }
 
+// identifies a bank, rows in different banks can be hammered independently
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Ord, PartialOrd)]
+pub struct BankKey {
+    pub chan: u8,
+    pub dimm: u8,
+    pub rank: u8,
+    pub bank: u8,
+}
+
+impl BankKey {
+    // row aligned address of a row in this bank
+    pub fn row(&self, row: u16) -> DramAddr {
+        DramAddr {
+            chan: self.chan,
+            dimm: self.dimm,
+            rank: self.rank,
+            bank: self.bank,
+            row,
+            ..DramAddr::new()
+        }
+    }
+}
+
 impl DramAddr {
     pub(crate) fn new() -> DramAddr {
         DramAddr {
@@ -26,6 +49,15 @@ impl DramAddr {
         }
     }
 
+    pub fn bank_key(&self) -> BankKey {
+        BankKey {
+            chan: self.chan,
+            dimm: self.dimm,
+            rank: self.rank,
+            bank: self.bank,
+        }
+    }
+
     pub fn byte_align(&mut self) {
         self.bit = 0;
     }
@@ -52,6 +84,17 @@ impl DramAddr {
         new
     }
 
+    // the row at distance d, negative distances are above, None if it does not exist
+    pub fn row_at(&self, d: i32) -> Option<Self> {
+        let row = self.row as i32 + d;
+        if row < 0 || row > u16::MAX as i32 {
+            return None;
+        }
+        let mut new = self.clone();
+        new.row = row as u16;
+        Some(new)
+    }
+
     pub fn row_above(&self) -> Self {
         let mut new = self.clone();
         new.row -= 1;
@@ -0,0 +1,291 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+// anonymous user memory comes from movable page blocks
+const USER_MIGRATE_TYPE: &str = "Movable";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Watermarks {
+    pub min: usize,
+    pub low: usize,
+    pub high: usize,
+}
+
+// one zone of one node, all counts in pages
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Zone {
+    pub node: usize,
+    pub name: String,
+    // free blocks per order from buddyinfo
+    pub free_blocks: Vec<usize>,
+    // free blocks per order and migrate type from pagetypeinfo, needs root
+    pub by_type: BTreeMap<String, Vec<usize>>,
+    pub free_pages: Option<usize>,
+    pub managed: Option<usize>,
+    pub watermarks: Option<Watermarks>,
+}
+
+impl Zone {
+    // free blocks per order user allocations are served from
+    pub(crate) fn user_blocks(&self) -> &[usize] {
+        self.by_type
+            .get(USER_MIGRATE_TYPE)
+            .map_or(&self.free_blocks, |b| b)
+    }
+
+    pub(crate) fn free_pages_total(&self) -> usize {
+        self.free_pages
+            .unwrap_or_else(|| self.free_blocks.iter().enumerate().map(|(o, n)| n << o).sum())
+    }
+
+    // has a free block of at least the given order
+    pub(crate) fn has_block(&self, order: usize) -> bool {
+        self.user_blocks().iter().skip(order).any(|&n| n > 0)
+    }
+
+    // pages in free blocks below the given order
+    pub(crate) fn pages_below(&self, order: usize) -> usize {
+        self.user_blocks()
+            .iter()
+            .take(order)
+            .enumerate()
+            .map(|(o, n)| n << o)
+            .sum()
+    }
+}
+
+// how much to allocate before the next allocation splits a block of the requested order
+#[derive(Clone, Debug)]
+pub(crate) struct DrainPlan {
+    // zone the contiguous block is expected to come from
+    pub zone: String,
+    pub pages: usize,
+    // the drain takes a zone below its low watermark, kswapd or reclaim will interfere
+    pub crosses_watermark: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct BuddyState {
+    pub zones: Vec<Zone>,
+}
+
+// "Node 0, zone   Normal" -> (0, "Normal")
+fn parse_node_zone(s: &str) -> Option<(usize, String)> {
+    let mut parts = s.split(',');
+    let node = parts.next()?.trim().trim_start_matches("Node").trim().parse().ok()?;
+    let zone = parts.next()?.trim().trim_start_matches("zone").trim();
+    Some((node, zone.to_string()))
+}
+
+fn parse_counts(s: &str) -> Vec<usize> {
+    s.split_whitespace().filter_map(|n| n.parse().ok()).collect()
+}
+
+// zones with their free blocks per order
+pub(crate) fn parse_buddyinfo(buddyinfo: &str) -> Vec<Zone> {
+    let mut zones = vec![];
+    for line in buddyinfo.lines() {
+        // "Node 0, zone   Normal   3621   1605 ..."
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.len() < 4 {
+            continue;
+        }
+        if let Some((node, name)) = parse_node_zone(&words[..4].join(" ")) {
+            zones.push(Zone {
+                node,
+                name,
+                free_blocks: parse_counts(&words[4..].join(" ")),
+                ..Default::default()
+            });
+        }
+    }
+    zones
+}
+
+// (node, zone) -> (free pages, managed pages, watermarks)
+pub(crate) fn parse_zoneinfo(zoneinfo: &str) -> BTreeMap<(usize, String), (usize, usize, Watermarks)> {
+    let mut zones = BTreeMap::new();
+    let mut current = None;
+    let mut free = 0;
+    let mut managed = 0;
+    let mut wm = Watermarks::default();
+
+    for line in zoneinfo.lines() {
+        if line.starts_with("Node") {
+            if let Some(key) = current.take() {
+                zones.insert(key, (free, managed, wm.clone()));
+            }
+            current = parse_node_zone(line);
+            free = 0;
+            managed = 0;
+            wm = Watermarks::default();
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let value = |i: usize| words.get(i).and_then(|v| v.parse().ok()).unwrap_or(0);
+        match (words.first(), words.get(1)) {
+            (Some(&"pages"), Some(&"free")) => free = value(2),
+            (Some(&"min"), _) => wm.min = value(1),
+            (Some(&"low"), _) => wm.low = value(1),
+            (Some(&"high"), _) => wm.high = value(1),
+            (Some(&"managed"), _) => managed = value(1),
+            _ => {}
+        }
+    }
+    if let Some(key) = current {
+        zones.insert(key, (free, managed, wm));
+    }
+    zones
+}
+
+// (node, zone) -> migrate type -> free blocks per order
+pub(crate) fn parse_pagetypeinfo(pagetypeinfo: &str) -> BTreeMap<(usize, String), BTreeMap<String, Vec<usize>>> {
+    let mut zones: BTreeMap<_, BTreeMap<_, _>> = BTreeMap::new();
+    for line in pagetypeinfo.lines() {
+        // "Node    0, zone   Normal, type    Movable   3619   1604 ..."
+        let parts: Vec<&str> = line.splitn(3, ',').collect();
+        if parts.len() < 3 || !parts[2].trim_start().starts_with("type") {
+            continue;
+        }
+        let key = match parse_node_zone(&format!("{},{}", parts[0], parts[1])) {
+            Some(k) => k,
+            None => continue,
+        };
+        let rest = parts[2].trim_start().trim_start_matches("type").trim_start();
+        let ty = rest.split_whitespace().next().unwrap_or("");
+        zones
+            .entry(key)
+            .or_default()
+            .insert(ty.to_string(), parse_counts(&rest[ty.len()..]));
+    }
+    zones
+}
+
+impl BuddyState {
+    // pagetypeinfo and zoneinfo are optional, predictions fall back to buddyinfo only
+    pub(crate) fn parse(buddyinfo: &str, zoneinfo: Option<&str>, pagetypeinfo: Option<&str>) -> Self {
+        let mut zones = parse_buddyinfo(buddyinfo);
+        let infos = zoneinfo.map(parse_zoneinfo).unwrap_or_default();
+        let mut types = pagetypeinfo.map(parse_pagetypeinfo).unwrap_or_default();
+
+        for z in zones.iter_mut() {
+            let key = (z.node, z.name.clone());
+            if let Some((free, managed, wm)) = infos.get(&key) {
+                z.free_pages = Some(*free);
+                z.managed = Some(*managed);
+                z.watermarks = Some(wm.clone());
+            }
+            if let Some(t) = types.remove(&key) {
+                z.by_type = t;
+            }
+        }
+        BuddyState { zones }
+    }
+
+    pub(crate) fn read() -> Result<Self, String> {
+        let buddyinfo = fs::read_to_string("/proc/buddyinfo").map_err(|e| format!("/proc/buddyinfo: {}", e))?;
+        let zoneinfo = fs::read_to_string("/proc/zoneinfo").ok();
+        // only readable by root
+        let pagetypeinfo = fs::read_to_string("/proc/pagetypeinfo").ok();
+        Ok(BuddyState::parse(
+            &buddyinfo,
+            zoneinfo.as_deref(),
+            pagetypeinfo.as_deref(),
+        ))
+    }
+
+    // zones of a node, lowest (DMA) first as the kernel lists them
+    pub(crate) fn node_zones(&self, node: usize) -> Vec<&Zone> {
+        self.zones.iter().filter(|z| z.node == node).collect()
+    }
+
+    // user allocations are served from the highest zone first and fall back to lower ones
+    // in the first zone with a large enough block, all smaller blocks are used up first
+    pub(crate) fn drain_plan(&self, node: usize, order: usize) -> Option<DrainPlan> {
+        let mut pages = 0;
+        for z in self.node_zones(node).into_iter().rev() {
+            let low = z.watermarks.as_ref().map_or(0, |w| w.low);
+            if !z.has_block(order) {
+                // nothing to split here, the zone has to be drained down to its watermark
+                pages += z.free_pages_total().saturating_sub(low);
+                continue;
+            }
+            let below = z.pages_below(order);
+            pages += below;
+            return Some(DrainPlan {
+                zone: z.name.clone(),
+                pages,
+                crosses_watermark: z.free_pages_total().saturating_sub(below) < low,
+            });
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // captured from a single node machine
+    const BUDDYINFO: &str = include_str!("../fixtures/proc/buddyinfo");
+    const ZONEINFO: &str = include_str!("../fixtures/proc/zoneinfo");
+    const PAGETYPEINFO: &str = include_str!("../fixtures/proc/pagetypeinfo");
+
+    #[test]
+    fn buddyinfo_snapshot() {
+        let zones = parse_buddyinfo(BUDDYINFO);
+        let names: Vec<&str> = zones.iter().map(|z| z.name.as_str()).collect();
+        assert_eq!(names, vec!["DMA", "DMA32", "Normal"]);
+        assert!(zones.iter().all(|z| z.node == 0));
+        assert_eq!(zones[2].free_blocks, vec![4702, 2473, 1015, 442, 170, 82, 12, 8, 2, 2, 22]);
+    }
+
+    #[test]
+    fn zoneinfo_snapshot() {
+        let zones = parse_zoneinfo(ZONEINFO);
+        assert_eq!(zones.len(), 5);
+        let normal = &zones[&(0, String::from("Normal"))];
+        assert_eq!(normal.0, 48386);
+        assert_eq!(normal.1, 425984);
+        assert_eq!(normal.2, Watermarks { min: 5977, low: 7471, high: 8965 });
+        // the per-node stats of the first zone are not mistaken for zone counters
+        let dma = &zones[&(0, String::from("DMA"))];
+        assert_eq!((dma.0, dma.1), (3840, 3840));
+        assert_eq!(dma.2, Watermarks { min: 53, low: 66, high: 79 });
+    }
+
+    #[test]
+    fn pagetypeinfo_snapshot() {
+        let zones = parse_pagetypeinfo(PAGETYPEINFO);
+        assert_eq!(zones.len(), 3);
+        let normal = &zones[&(0, String::from("Normal"))];
+        assert_eq!(normal.len(), 5);
+        assert_eq!(normal["Movable"], vec![4637, 2413, 988, 430, 169, 80, 11, 6, 1, 1, 22]);
+        assert_eq!(normal["Unmovable"], vec![1, 59, 26, 10, 0, 1, 0, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn drain_plan_from_snapshot() {
+        let state = BuddyState::parse(BUDDYINFO, Some(ZONEINFO), Some(PAGETYPEINFO));
+        let free: usize = state.node_zones(0).iter().map(|z| z.free_pages_total()).sum();
+        assert_eq!(free, 3840 + 774334 + 48386);
+
+        // the Normal zone has a movable order 9 block, the movable blocks below it go first
+        let plan = state.drain_plan(0, 9).unwrap();
+        assert_eq!(plan.zone, "Normal");
+        assert_eq!(plan.pages, 4637 + 2413 * 2 + 988 * 4 + 430 * 8 + 169 * 16 + 80 * 32 + 11 * 64 + 6 * 128 + 256);
+        assert!(!plan.crosses_watermark);
+        assert!(state.drain_plan(0, 11).is_none());
+        assert!(state.drain_plan(1, 9).is_none());
+    }
+
+    #[test]
+    fn buddyinfo_only() {
+        let state = BuddyState::parse(BUDDYINFO, None, None);
+        let normal = &state.node_zones(0)[2];
+        assert_eq!(normal.user_blocks(), normal.free_blocks.as_slice());
+        assert_eq!(normal.watermarks, None);
+        let plan = state.drain_plan(0, 10).unwrap();
+        assert_eq!(plan.zone, "Normal");
+    }
+}
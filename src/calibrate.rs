@@ -0,0 +1,268 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::architecture::DramAddr;
+use crate::config::Config;
+use crate::hammer::{reads_per_refresh, HammerKernel};
+use crate::memmap::MemMap;
+
+// number of same-bank pairs and measurements per pair
+const CAL_PAIRS: usize = 4;
+const CAL_REPS: usize = 5;
+// samples further than this many (scaled) median absolute deviations from the median are dropped
+const MAX_MAD: f64 = 3.0;
+const Z_95: f64 = 1.96;
+// measured refresh periods vary between runs, a stored calibration is reused within this many percent
+const PERIOD_TOLERANCE_PCT: usize = 5;
+
+#[derive(Clone, Debug)]
+pub(crate) struct Calibration {
+    pub kernel: HammerKernel,
+    pub refresh_period_us: usize,
+    pub reads_per_refresh: usize,
+    // activations of each aggressor within one refresh window, the unit of HammerReport
+    pub activations_per_refresh: f64,
+    // half-width of the 95% confidence interval of activations_per_refresh
+    pub ci95: f64,
+    pub samples: usize,
+    pub rejected: usize,
+}
+
+impl Calibration {
+    pub(crate) fn reads_per_us(&self) -> f64 {
+        self.reads_per_refresh as f64 / self.refresh_period_us as f64
+    }
+
+    fn serialize(&self) -> String {
+        format!(
+            "kernel={:?}\nrefresh_period_us={}\nreads_per_refresh={}\nactivations_per_refresh={}\nci95={}\nsamples={}\nrejected={}\n",
+            self.kernel,
+            self.refresh_period_us,
+            self.reads_per_refresh,
+            self.activations_per_refresh,
+            self.ci95,
+            self.samples,
+            self.rejected
+        )
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        let kv: BTreeMap<&str, &str> = s
+            .lines()
+            .filter_map(|l| {
+                let mut it = l.splitn(2, '=');
+                Some((it.next()?.trim(), it.next()?.trim()))
+            })
+            .collect();
+
+        Some(Calibration {
+            kernel: parse_kernel(kv.get("kernel")?)?,
+            refresh_period_us: kv.get("refresh_period_us")?.parse().ok()?,
+            reads_per_refresh: kv.get("reads_per_refresh")?.parse().ok()?,
+            activations_per_refresh: kv.get("activations_per_refresh")?.parse().ok()?,
+            ci95: kv.get("ci95")?.parse().ok()?,
+            samples: kv.get("samples")?.parse().ok()?,
+            rejected: kv.get("rejected")?.parse().ok()?,
+        })
+    }
+}
+
+fn parse_kernel(s: &str) -> Option<HammerKernel> {
+    crate::hammer::ALL_KERNELS
+        .iter()
+        .cloned()
+        .find(|k| format!("{:?}", k) == s)
+}
+
+fn machine_id() -> String {
+    fs::read_to_string("/etc/machine-id")
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|_| String::from("unknown"))
+}
+
+// calibration is stored per machine, RAMBLEED_CALIBRATION overrides the location
+pub(crate) fn calibration_path() -> PathBuf {
+    if let Ok(p) = std::env::var("RAMBLEED_CALIBRATION") {
+        return PathBuf::from(p);
+    }
+    let mut p = std::env::var("HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("."));
+    p.push(".cache/rambleed-rs");
+    p.push(format!("{}.calibration", machine_id()));
+    p
+}
+
+fn same_period(a: usize, b: usize) -> bool {
+    a.abs_diff(b) * 100 <= a.max(b) * PERIOD_TOLERANCE_PCT
+}
+
+pub(crate) fn load_calibration(c: &Config) -> Option<Calibration> {
+    let cal = Calibration::parse(&fs::read_to_string(calibration_path()).ok()?)?;
+    if cal.kernel != c.kernel || !same_period(cal.refresh_period_us, c.refresh_period()) {
+        return None;
+    }
+    Some(cal)
+}
+
+pub(crate) fn save_calibration(cal: &Calibration) -> std::io::Result<()> {
+    let path = calibration_path();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, cal.serialize())
+}
+
+// up to n row conflict pairs, each from a different bank
+pub(crate) fn same_bank_pairs(mem: &MemMap, n: usize) -> Vec<(DramAddr, DramAddr)> {
+    mem.index()
+        .keys()
+        .filter_map(|b| mem.same_bank_pair(b))
+        .take(n)
+        .collect()
+}
+
+fn median(sorted: &[usize]) -> f64 {
+    median_f64(&sorted.iter().map(|&s| s as f64).collect::<Vec<f64>>())
+}
+
+fn median_f64(sorted: &[f64]) -> f64 {
+    let n = sorted.len();
+    if n == 0 {
+        return 0.0;
+    }
+    if n % 2 == 1 {
+        sorted[n / 2]
+    } else {
+        (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0
+    }
+}
+
+// drop samples too far from the median, using the median absolute deviation
+// never empty for a non-empty input, without a spread all samples are kept
+pub(crate) fn reject_outliers(samples: &[usize]) -> Vec<usize> {
+    if samples.len() < 3 {
+        return samples.to_vec();
+    }
+    let mut sorted = samples.to_vec();
+    sorted.sort();
+    let med = median(&sorted);
+
+    let mut devs: Vec<f64> = sorted.iter().map(|&s| (s as f64 - med).abs()).collect();
+    devs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    // 1.4826 scales the MAD to the standard deviation of a normal distribution
+    let mad = 1.4826 * median_f64(&devs);
+    let kept: Vec<usize> = if mad == 0.0 {
+        sorted.iter().cloned().filter(|&s| s as f64 == med).collect()
+    } else {
+        sorted
+            .iter()
+            .cloned()
+            .filter(|&s| (s as f64 - med).abs() / mad <= MAX_MAD)
+            .collect()
+    };
+    if kept.is_empty() {
+        return sorted;
+    }
+    kept
+}
+
+// mean and half-width of the 95% confidence interval
+pub(crate) fn mean_ci95(samples: &[usize]) -> (f64, f64) {
+    let n = samples.len() as f64;
+    if samples.is_empty() {
+        return (0.0, 0.0);
+    }
+    let mean = samples.iter().sum::<usize>() as f64 / n;
+    if samples.len() < 2 {
+        return (mean, 0.0);
+    }
+    let var = samples.iter().map(|&s| (s as f64 - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    (mean, Z_95 * var.sqrt() / n.sqrt())
+}
+
+pub(crate) fn calibrate(mem: &MemMap, c: &Config) -> Option<Calibration> {
+    let refresh_period_us = c.refresh_period();
+    let pairs = same_bank_pairs(mem, CAL_PAIRS);
+    if pairs.is_empty() {
+        return None;
+    }
+
+    let mut samples = Vec::new();
+    for (a1, a2) in &pairs {
+        let (a1, a2) = match mem.aggressor_pair(a1, a2, c) {
+            Some((a1, a2)) => (a1.as_ptr(), a2.as_ptr()),
+            None => continue,
+        };
+        for _ in 0..CAL_REPS {
+            samples.push(reads_per_refresh(c.kernel, a1, a2, refresh_period_us));
+        }
+    }
+
+    let kept = reject_outliers(&samples);
+    let (mean_reads, ci_reads) = mean_ci95(&kept);
+    let mut sorted = kept.clone();
+    sorted.sort();
+
+    // every read of the two aggressors activates each of them once
+    Some(Calibration {
+        kernel: c.kernel,
+        refresh_period_us,
+        reads_per_refresh: median(&sorted) as usize,
+        activations_per_refresh: mean_reads,
+        ci95: ci_reads,
+        samples: samples.len(),
+        rejected: samples.len() - kept.len(),
+    })
+}
+
+// reuse the stored calibration of this machine if it matches the config
+pub(crate) fn load_or_calibrate(mem: &MemMap, c: &Config) -> Option<Calibration> {
+    if let Some(cal) = load_calibration(c) {
+        println!("Loaded calibration from {:?}", calibration_path());
+        return Some(cal);
+    }
+
+    let cal = calibrate(mem, c)?;
+    if let Err(e) = save_calibration(&cal) {
+        println!("Failed to save calibration: {}", e);
+    }
+    Some(cal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_outliers_half_median() {
+        // the median is 1.5, no sample equals it
+        assert_eq!(reject_outliers(&[1, 2, 1, 2, 1, 2]), vec![1, 1, 1, 2, 2, 2]);
+    }
+
+    #[test]
+    fn reject_outliers_without_spread() {
+        assert_eq!(reject_outliers(&[5, 5, 100, 5]), vec![5, 5, 5]);
+    }
+
+    #[test]
+    fn reject_outliers_drops_far_samples() {
+        assert_eq!(reject_outliers(&[100, 102, 98, 101, 99, 5000]), vec![98, 99, 100, 101, 102]);
+    }
+
+    #[test]
+    fn measured_periods_match_within_tolerance() {
+        assert!(same_period(64_000, 63_898));
+        assert!(same_period(63_898, 64_000));
+        assert!(same_period(64_000, 61_000));
+        assert!(!same_period(64_000, 32_000));
+        assert!(!same_period(0, 64_000));
+    }
+
+    #[test]
+    fn median_of_empty() {
+        assert_eq!(median(&[]), 0.0);
+        assert_eq!(median(&[1, 4]), 2.5);
+    }
+}
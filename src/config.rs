@@ -1,8 +1,23 @@
 use crate::architecture::Architecture;
+use crate::hammer::{HammerAmount, HammerKernel};
+use crate::refresh::RefCadence;
 
-pub struct Config {
-    pub aligned_bits: usize,
+pub(crate) struct Config {
+    // loop iterations per hammering, derived from amount by calibration
     pub reads_per_hammer: usize,
+    pub amount: HammerAmount,
+    pub kernel: HammerKernel,
     pub contiguous_dram_addr: usize,
-    pub arch: Box<dyn Architecture>,
+    pub arch: Box<dyn Architecture + Send + Sync>,
+    // measured refresh period, overrides the architecture default
+    pub refresh_period_us: Option<usize>,
+    // hammer synchronized to refresh commands if set
+    pub ref_sync: Option<RefCadence>,
+}
+
+impl Config {
+    pub fn refresh_period(&self) -> usize {
+        self.refresh_period_us
+            .unwrap_or_else(|| self.arch.refresh_period())
+    }
 }
@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+
+use crate::architecture::DramAddr;
+use crate::config::Config;
+use crate::memmap::MemMap;
+use crate::refresh::time_access;
+
+const REPS_PER_PAIR: usize = 50;
+const BUCKET_WIDTH: u64 = 10;
+
+// relation of two addresses according to the address mapping
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PairClass {
+    RowHit,
+    RowConflict,
+    OtherBank,
+}
+
+impl PairClass {
+    pub(crate) fn of(a1: &DramAddr, a2: &DramAddr) -> Self {
+        if a1.bank_key() != a2.bank_key() {
+            PairClass::OtherBank
+        } else if a1.row == a2.row {
+            PairClass::RowHit
+        } else {
+            PairClass::RowConflict
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            PairClass::RowHit => 0,
+            PairClass::RowConflict => 1,
+            PairClass::OtherBank => 2,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct PairTiming {
+    pub off1: usize,
+    pub off2: usize,
+    pub class: PairClass,
+    pub latency: u64,
+}
+
+// median latency of accessing a1 and a2 in a loop
+pub(crate) fn pair_latency(a1: *const u8, a2: *const u8, reps: usize) -> u64 {
+    let mut lat: Vec<u64> = (0..reps).map(|_| time_access(a1, a2).1).collect();
+    lat.sort();
+    lat[lat.len() / 2]
+}
+
+// xorshift, good enough to spread pairs over the buffer
+fn next_rand(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+// time n random cache line pairs of mem
+pub(crate) fn measure_pairs(mem: &MemMap, n: usize, c: &Config) -> Vec<PairTiming> {
+    let lines = mem.len() / 64;
+    if lines == 0 {
+        return vec![];
+    }
+    let mut state = 0x2545_f491_4f6c_dd1d;
+    let mut timings = Vec::with_capacity(n);
+
+    for _ in 0..n {
+        let off1 = (next_rand(&mut state) as usize % lines) * 64;
+        let off2 = (next_rand(&mut state) as usize % lines) * 64;
+        if off1 == off2 {
+            continue;
+        }
+        let class = match (mem.offset_to_dram(off1, c), mem.offset_to_dram(off2, c)) {
+            (Some(d1), Some(d2)) => PairClass::of(&d1, &d2),
+            _ => continue,
+        };
+        let (a1, a2) = match (mem.at_offset(off1), mem.at_offset(off2)) {
+            (Some(a1), Some(a2)) => (a1, a2),
+            _ => continue,
+        };
+        timings.push(PairTiming {
+            off1,
+            off2,
+            class,
+            latency: pair_latency(a1.as_ptr(), a2.as_ptr(), REPS_PER_PAIR),
+        });
+    }
+    timings
+}
+
+// latency bucket -> number of row hits, row conflicts and other bank pairs
+pub(crate) fn histogram(timings: &[PairTiming]) -> BTreeMap<u64, [usize; 3]> {
+    let mut hist = BTreeMap::new();
+    for t in timings {
+        hist.entry(t.latency / BUCKET_WIDTH * BUCKET_WIDTH)
+            .or_insert([0; 3])[t.class.index()] += 1;
+    }
+    hist
+}
+
+// threshold that separates the latencies into two classes with minimal within-class variance
+pub(crate) fn otsu_threshold(latencies: &[u64]) -> Option<u64> {
+    let mut lat = latencies.to_vec();
+    lat.sort();
+    let n = lat.len() as f64;
+    let total: f64 = lat.iter().map(|&l| l as f64).sum();
+
+    let mut best = None;
+    let mut best_var = 0.0;
+    let mut sum_lo = 0.0;
+    for i in 0..lat.len().saturating_sub(1) {
+        sum_lo += lat[i] as f64;
+        if lat[i] == lat[i + 1] {
+            continue;
+        }
+        let w_lo = (i + 1) as f64 / n;
+        let w_hi = 1.0 - w_lo;
+        let mean_lo = sum_lo / (i + 1) as f64;
+        let mean_hi = (total - sum_lo) / (n - (i + 1) as f64);
+        let var = w_lo * w_hi * (mean_lo - mean_hi).powi(2);
+        if var > best_var {
+            best_var = var;
+            best = Some(lat[i + 1]);
+        }
+    }
+    best
+}
+
+// threshold with the fewest misclassified pairs, conflicts are at or above the threshold
+pub(crate) fn labeled_threshold(timings: &[PairTiming]) -> Option<u64> {
+    let mut sorted: Vec<&PairTiming> = timings.iter().collect();
+    sorted.sort_by_key(|t| t.latency);
+    let conflicts = sorted.iter().filter(|t| t.class == PairClass::RowConflict).count();
+    if conflicts == 0 || conflicts == sorted.len() {
+        return None;
+    }
+
+    // errors when everything at index i and above is classified as a conflict
+    let mut errors = sorted.len() - conflicts;
+    let mut best = (errors, sorted[0].latency);
+    for i in 0..sorted.len() {
+        if sorted[i].class == PairClass::RowConflict {
+            errors += 1;
+        } else {
+            errors -= 1;
+        }
+        if i + 1 < sorted.len() && sorted[i].latency != sorted[i + 1].latency && errors < best.0 {
+            best = (errors, sorted[i + 1].latency);
+        }
+    }
+    Some(best.1)
+}
+
+// same-bank oracle: a pair slower than the threshold is in the same bank but different rows
+#[derive(Clone, Debug)]
+pub(crate) struct ConflictOracle {
+    pub threshold: u64,
+}
+
+impl ConflictOracle {
+    // prefers the address mapping as ground truth and falls back to the latency distribution alone
+    pub(crate) fn from_timings(timings: &[PairTiming]) -> Option<Self> {
+        let threshold = labeled_threshold(timings).or_else(|| {
+            otsu_threshold(&timings.iter().map(|t| t.latency).collect::<Vec<u64>>())
+        })?;
+        Some(ConflictOracle { threshold })
+    }
+}
+
+pub(crate) fn export_timings_csv(timings: &[PairTiming], path: &str) -> io::Result<()> {
+    let mut f = File::create(path)?;
+    writeln!(f, "off1,off2,class,latency")?;
+    for t in timings {
+        writeln!(f, "{},{},{:?},{}", t.off1, t.off2, t.class, t.latency)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn export_histogram_csv(timings: &[PairTiming], path: &str) -> io::Result<()> {
+    let mut f = File::create(path)?;
+    writeln!(f, "latency,row_hit,row_conflict,other_bank")?;
+    for (bucket, counts) in histogram(timings) {
+        writeln!(f, "{},{},{},{}", bucket, counts[0], counts[1], counts[2])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing(class: PairClass, latency: u64) -> PairTiming {
+        PairTiming { off1: 0, off2: 64, class, latency }
+    }
+
+    #[test]
+    fn otsu_splits_bimodal_latencies() {
+        let lat = [200, 210, 205, 198, 340, 350, 345, 202, 355];
+        assert_eq!(otsu_threshold(&lat), Some(340));
+    }
+
+    #[test]
+    fn otsu_without_two_classes() {
+        assert_eq!(otsu_threshold(&[]), None);
+        assert_eq!(otsu_threshold(&[250]), None);
+        assert_eq!(otsu_threshold(&[250, 250, 250]), None);
+    }
+
+    #[test]
+    fn labeled_threshold_minimizes_errors() {
+        use PairClass::*;
+        // one slow hit and one fast conflict are misclassified by any threshold
+        let timings = [
+            timing(RowHit, 200),
+            timing(RowConflict, 205),
+            timing(OtherBank, 210),
+            timing(RowHit, 220),
+            timing(RowConflict, 340),
+            timing(RowHit, 345),
+            timing(RowConflict, 350),
+            timing(RowConflict, 360),
+        ];
+        assert_eq!(labeled_threshold(&timings), Some(340));
+    }
+
+    #[test]
+    fn labeled_threshold_needs_both_classes() {
+        assert_eq!(labeled_threshold(&[]), None);
+        assert_eq!(labeled_threshold(&[timing(PairClass::RowHit, 200), timing(PairClass::OtherBank, 300)]), None);
+        assert_eq!(labeled_threshold(&[timing(PairClass::RowConflict, 300), timing(PairClass::RowConflict, 350)]), None);
+    }
+}
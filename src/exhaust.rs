@@ -0,0 +1,289 @@
+use std::fs;
+
+use vm_info::page_size;
+
+use crate::alloc::{map_eager, unmap, verify_contiguity, AllocError, Allocation, AllocationStrategy, Contiguity};
+use crate::buddy::BuddyState;
+use crate::config::Config;
+use crate::conflict::{measure_pairs, otsu_threshold, PairClass};
+use crate::memmap::MemMap;
+use crate::numa::NodeBinding;
+use crate::phys::TimingAlignment;
+
+const BLOCK_BITS: usize = 21;
+const BLOCK_SIZE: usize = 1 << BLOCK_BITS;
+// stop draining after this many steps without progress, the kernel refills the low orders
+const MAX_STALLS: usize = 3;
+// random pairs timed to check a candidate without pagemap
+const TIMING_PAIRS: usize = 400;
+// fraction of pairs whose latency has to agree with the address mapping
+const MIN_AGREEMENT: f64 = 0.9;
+
+// drain the low orders of the buddy allocator until the next allocation splits a 2 MB block
+pub(crate) struct BuddyExhaustion {
+    pub node: usize,
+    // bytes populated per step, buddyinfo is re-read after every step
+    pub step: usize,
+    // 2 MB blocks tried after draining
+    pub candidates: usize,
+    // bytes left untouched below the cgroup memory limit
+    pub cgroup_headroom: usize,
+}
+
+impl Default for BuddyExhaustion {
+    fn default() -> Self {
+        BuddyExhaustion {
+            node: 0,
+            step: 16 << 20,
+            candidates: 8,
+            cgroup_headroom: 64 << 20,
+        }
+    }
+}
+
+// cgroup of this process from /proc/self/cgroup, v2 ("0::/path") or the v1 memory controller
+pub(crate) fn parse_cgroup(cgroup: &str) -> Option<(bool, String)> {
+    for line in cgroup.lines() {
+        let parts: Vec<&str> = line.splitn(3, ':').collect();
+        if parts.len() != 3 {
+            continue;
+        }
+        if parts[1].split(',').any(|c| c == "memory") {
+            return Some((false, parts[2].to_string()));
+        }
+        if parts[0] == "0" && parts[1].is_empty() {
+            return Some((true, parts[2].to_string()));
+        }
+    }
+    None
+}
+
+fn read_bytes(path: &str) -> Option<usize> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+// bytes this process may still allocate before hitting its cgroup limit, None without a limit
+pub(crate) fn cgroup_available() -> Option<usize> {
+    let (v2, path) = parse_cgroup(&fs::read_to_string("/proc/self/cgroup").ok()?)?;
+    let (limit, usage) = if v2 {
+        let dir = format!("/sys/fs/cgroup{}", path);
+        (
+            read_bytes(&format!("{}/memory.max", dir))?,
+            read_bytes(&format!("{}/memory.current", dir))?,
+        )
+    } else {
+        let dir = format!("/sys/fs/cgroup/memory{}", path);
+        (
+            read_bytes(&format!("{}/memory.limit_in_bytes", dir))?,
+            read_bytes(&format!("{}/memory.usage_in_bytes", dir))?,
+        )
+    };
+    Some(limit.saturating_sub(usage))
+}
+
+// pages of the node that can be taken without going below the high watermarks
+fn headroom_pages(state: &BuddyState, node: usize) -> usize {
+    state
+        .node_zones(node)
+        .iter()
+        .map(|z| {
+            let high = z.watermarks.as_ref().map_or(0, |w| w.high);
+            z.free_pages_total().saturating_sub(high)
+        })
+        .sum()
+}
+
+// without pagemap: if the block is contiguous and aligned, latencies follow the address mapping
+pub(crate) fn timing_contiguity(buf: *mut u8, len: usize, c: &Config) -> Result<Contiguity, AllocError> {
+    // buddy blocks of 2 MB are aligned to 2 MB
+    let aligned = TimingAlignment { base: buf, offset: 0, bits: BLOCK_BITS };
+    // the candidate is released or kept by the caller
+    let mem = unsafe { MemMap::view(buf, len, &aligned, c) };
+    let timings = measure_pairs(&mem, TIMING_PAIRS, c);
+    let threshold = otsu_threshold(&timings.iter().map(|t| t.latency).collect::<Vec<u64>>())
+        .ok_or(AllocError::NotContiguous { offset: 0 })?;
+    if !timings.iter().any(|t| t.class == PairClass::RowConflict) {
+        return Err(AllocError::NotContiguous { offset: 0 });
+    }
+
+    let agree = timings
+        .iter()
+        .filter(|t| (t.latency >= threshold) == (t.class == PairClass::RowConflict))
+        .count();
+    let agreement = agree as f64 / timings.len() as f64;
+    if agreement < MIN_AGREEMENT {
+        // the first disagreeing pair hints where the block breaks
+        let offset = timings
+            .iter()
+            .find(|t| (t.latency >= threshold) != (t.class == PairClass::RowConflict))
+            .map_or(0, |t| t.off1.max(t.off2));
+        return Err(AllocError::NotContiguous { offset });
+    }
+    Ok(Contiguity::Timed { agreement })
+}
+
+fn candidate_contiguity(buf: *mut u8, len: usize, c: &Config) -> Result<Contiguity, AllocError> {
+    match verify_contiguity(buf, len) {
+        Err(AllocError::NoFrameNumbers) => timing_contiguity(buf, len, c),
+        r => r,
+    }
+}
+
+// mappings populated while draining, released together
+type Held = Vec<(*mut u8, usize)>;
+
+impl BuddyExhaustion {
+    // populate memory in steps until the low orders are empty, stops early under memory pressure
+    // returns the drained mappings and whether draining completed
+    fn drain(&self, order: usize, page_sz: usize) -> Result<(Held, bool), AllocError> {
+        let mut held = vec![];
+        let mut drained = 0;
+        let mut last_pages = usize::MAX;
+        let mut stalls = 0;
+        let mut warned = false;
+
+        loop {
+            let state = match BuddyState::read() {
+                Ok(s) => s,
+                Err(e) => {
+                    release(&held);
+                    return Err(AllocError::Buddy(e));
+                }
+            };
+            let plan = match state.drain_plan(self.node, order) {
+                Some(p) => p,
+                None => return Ok((held, false)),
+            };
+            if plan.pages == 0 {
+                return Ok((held, true));
+            }
+
+            if plan.pages >= last_pages {
+                stalls += 1;
+                if stalls >= MAX_STALLS {
+                    println!("Buddy allocator does not drain, {} pages left", plan.pages);
+                    return Ok((held, false));
+                }
+            }
+            last_pages = plan.pages;
+
+            if plan.crosses_watermark && !warned {
+                println!("Draining {} pages from zone {} crosses its low watermark, reclaim may interfere", plan.pages, plan.zone);
+                warned = true;
+            }
+
+            let step = (plan.pages * page_sz).min(self.step);
+            if step / page_sz > headroom_pages(&state, self.node) {
+                println!("Stopped draining after {} bytes, node {} is close to its watermarks", drained, self.node);
+                return Ok((held, false));
+            }
+            if let Some(avail) = cgroup_available() {
+                if step + self.cgroup_headroom > avail {
+                    println!("Stopped draining after {} bytes, cgroup limit is {} bytes away", drained, avail);
+                    return Ok((held, false));
+                }
+            }
+
+            match map_eager(step) {
+                Ok(m) => held.push(m),
+                Err(e) => {
+                    release(&held);
+                    return Err(e);
+                }
+            }
+            drained += step;
+        }
+    }
+}
+
+fn release(held: &[(*mut u8, usize)]) {
+    for &(buf, len) in held {
+        unmap(buf, len);
+    }
+}
+
+impl AllocationStrategy for BuddyExhaustion {
+    fn name(&self) -> &'static str {
+        "buddy exhaustion"
+    }
+
+    fn allocate(&self, c: &Config) -> Result<Allocation, AllocError> {
+        let page_sz = page_size().unwrap_or(4096);
+        let order = BLOCK_BITS - page_sz.trailing_zeros() as usize;
+
+        // draining only helps if the candidates come from the drained node
+        // kernels without NUMA support have a single node and no memory policies
+        let _binding = NodeBinding::bind(self.node)
+            .map_err(|e| println!("Not binding to node {}: {}", self.node, e))
+            .ok();
+        let (mut held, complete) = self.drain(order, page_sz)?;
+        let drained: usize = held.iter().map(|m| m.1).sum();
+        println!("Drained {} bytes from node {}", drained, self.node);
+
+        // rejected candidates stay mapped so the next try gets fresh frames
+        let mut last_err = AllocError::NotContiguous { offset: 0 };
+        for i in 0..self.candidates {
+            let (buf, len) = match map_eager(BLOCK_SIZE) {
+                Ok(m) => m,
+                Err(e) => {
+                    last_err = e;
+                    break;
+                }
+            };
+            match candidate_contiguity(buf, len, c) {
+                Ok(contiguity) => {
+                    release(&held);
+                    return Ok(Allocation {
+                        mem: unsafe { MemMap::new(buf, len, &*contiguity.resolver(buf), c) },
+                        contiguity,
+                    });
+                }
+                Err(e) => {
+                    println!("Candidate {} rejected: {}", i, e);
+                    held.push((buf, len));
+                    last_err = e;
+                }
+            }
+        }
+
+        release(&held);
+        if !complete {
+            return Err(AllocError::MemoryPressure { drained });
+        }
+        Err(last_err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cgroup_v2() {
+        assert_eq!(parse_cgroup("0::/user.slice/session-2.scope\n"), Some((true, String::from("/user.slice/session-2.scope"))));
+    }
+
+    #[test]
+    fn cgroup_v1_memory_controller() {
+        let cgroup = "12:cpuset:/\n5:cpu,cpuacct:/user.slice\n4:memory:/user.slice/user-1000.slice\n0::/user.slice\n";
+        assert_eq!(parse_cgroup(cgroup), Some((false, String::from("/user.slice/user-1000.slice"))));
+    }
+
+    #[test]
+    fn cgroup_without_memory_controller() {
+        assert_eq!(parse_cgroup(""), None);
+        assert_eq!(parse_cgroup("12:cpuset:/\n5:cpu,cpuacct:/\n"), None);
+        assert_eq!(parse_cgroup("garbage\n"), None);
+    }
+
+    #[test]
+    fn headroom_stays_above_high_watermarks() {
+        let state = BuddyState::parse(
+            include_str!("../fixtures/proc/buddyinfo"),
+            Some(include_str!("../fixtures/proc/zoneinfo")),
+            None,
+        );
+        assert_eq!(headroom_pages(&state, 0), (3840 - 79) + (774334 - 16296) + (48386 - 8965));
+        assert_eq!(headroom_pages(&state, 1), 0);
+    }
+}
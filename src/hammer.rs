@@ -1,16 +1,230 @@
-use std::time::Instant;
+use std::arch::x86_64::__cpuid_count;
+use crate::config::Config;
+use crate::jit::{hammer_jit, jit_available};
+use crate::memmap::Aggressor;
+use crate::refresh::hammer_synchronized;
+use crate::timing::timer;
 
-const START_GRAN: usize = 0x100000;
+const MIN_GRAN: usize = 0x1000;
+const MAX_GRAN: usize = 0x10000000;
 const MAX_OVERSHOOT: f64 = 1.0 / 32.0;
 
-pub(crate) fn reads_per_refresh(a1: *const u8, a2: *const u8, refresh_period_us: usize) -> usize {
-    let mut reads_per_hammer = 0;
-    let mut gran = START_GRAN;
+// a kernel is only considered to cause row conflicts if hammering a row conflict pair
+// is at least this much slower than hammering two addresses in the same row
+const MIN_CONFLICT_RATIO: f64 = 1.2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum HammerKernel {
+    // mov + clflush, no fences
+    Clflush,
+    // mov + clflush, mfence after each pair
+    ClflushMfence,
+    // mov, lfence, clflush for each address
+    ClflushLfence,
+    // mov + clflushopt, mfence after each pair (clflushopt is weakly ordered)
+    ClflushoptMfence,
+    // non-temporal load + clflush
+    MovntdqaClflush,
+    // non-temporal store, mfence after each pair
+    MovntiMfence,
+    // mov + clflush from code generated at runtime, see jit.rs
+    Jit,
+}
+
+pub(crate) const ALL_KERNELS: [HammerKernel; 7] = [
+    HammerKernel::Clflush,
+    HammerKernel::ClflushMfence,
+    HammerKernel::ClflushLfence,
+    HammerKernel::ClflushoptMfence,
+    HammerKernel::MovntdqaClflush,
+    HammerKernel::MovntiMfence,
+    HammerKernel::Jit,
+];
+
+impl HammerKernel {
+    pub(crate) fn supported(&self) -> bool {
+        match self {
+            HammerKernel::ClflushoptMfence => has_clflushopt(),
+            HammerKernel::MovntdqaClflush => is_x86_feature_detected!("sse4.1"),
+            // needs a mapping that can be made executable
+            HammerKernel::Jit => jit_available(),
+            // clflush and movnti are part of SSE2, which every x86-64 CPU has
+            _ => true,
+        }
+    }
+}
+
+// how much to hammer, independent of CPU speed and kernel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum HammerAmount {
+    // raw loop iterations
+    Reads(usize),
+    // activations of each aggressor
+    Activations(usize),
+    // wall-clock time in microseconds
+    BudgetUs(usize),
+    // wall-clock time in refresh periods, the detected one if available
+    RefreshWindows(usize),
+}
+
+impl HammerAmount {
+    // loop iterations for a kernel doing reads_per_us iterations per microsecond
+    // every iteration activates each aggressor once
+    pub(crate) fn to_reads(self, reads_per_us: f64, refresh_period_us: usize) -> usize {
+        match self {
+            HammerAmount::Reads(n) => n,
+            HammerAmount::Activations(n) => n,
+            HammerAmount::BudgetUs(us) => (us as f64 * reads_per_us) as usize,
+            HammerAmount::RefreshWindows(n) => (n as f64 * refresh_period_us as f64 * reads_per_us) as usize,
+        }
+    }
+
+    // the amount selected by one of --reads, --activations, --budget-us or --windows in args
+    pub(crate) fn from_args(args: &[String]) -> Result<Option<Self>, String> {
+        let mut amount = None;
+        for (i, a) in args.iter().enumerate() {
+            let make: fn(usize) -> HammerAmount = match a.as_str() {
+                "--reads" => HammerAmount::Reads,
+                "--activations" => HammerAmount::Activations,
+                "--budget-us" => HammerAmount::BudgetUs,
+                "--windows" => HammerAmount::RefreshWindows,
+                _ => continue,
+            };
+            if amount.is_some() {
+                return Err("only one of --reads, --activations, --budget-us and --windows may be given".to_string());
+            }
+            let n = args
+                .get(i + 1)
+                .and_then(|n| n.parse().ok())
+                .ok_or_else(|| format!("{} needs a number", a))?;
+            amount = Some(make(n));
+        }
+        Ok(amount)
+    }
+}
 
+// what a hammer run actually achieved
+#[derive(Clone, Debug, Default)]
+pub(crate) struct HammerReport {
+    pub requested_reads: usize,
+    // activations of each aggressor (not of both together) within one refresh window
+    pub activations_per_refresh: usize,
+}
+
+impl HammerReport {
+    // num_reads spread over elapsed_us, at most num_reads fall into one refresh window
+    pub(crate) fn new(c: &Config, num_reads: usize, elapsed_us: u128) -> Self {
+        let window = c.refresh_period() as u128;
+        let activations_per_refresh = if elapsed_us <= window {
+            num_reads
+        } else {
+            (num_reads as u128 * window / elapsed_us) as usize
+        };
+        HammerReport {
+            requested_reads: num_reads,
+            activations_per_refresh,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct KernelReport {
+    pub kernel: HammerKernel,
+    pub activations_per_sec: f64,
+    pub conflict_ratio: f64,
+}
+
+// cpuid is only unsafe on older toolchains
+#[allow(unused_unsafe)]
+fn has_clflushopt() -> bool {
+    // CPUID.(EAX=07H, ECX=0H):EBX.CLFLUSHOPT[bit 23]
+    let leaf = unsafe { __cpuid_count(7, 0) };
+    leaf.ebx & (1 << 23) != 0
+}
+
+pub(crate) fn available_kernels() -> Vec<HammerKernel> {
+    ALL_KERNELS.iter().cloned().filter(|k| k.supported()).collect()
+}
+
+// hammer a row conflict pair (a1, a2) and a same-row pair (h1, h2) with the given kernel
+// every iteration of the conflict pair causes two activations
+pub(crate) fn measure_kernel(
+    kernel: HammerKernel,
+    a1: *const u8,
+    a2: *const u8,
+    h1: *const u8,
+    h2: *const u8,
+    num_reads: usize,
+) -> KernelReport {
+    let t = timer();
+    let t0 = t.now();
+    hammer_with(kernel, a1, a2, num_reads);
+    let t_conflict = t.elapsed_ns(t0);
+
+    let t0 = t.now();
+    hammer_with(kernel, h1, h2, num_reads);
+    let t_hit = t.elapsed_ns(t0);
+
+    KernelReport {
+        kernel,
+        activations_per_sec: (2 * num_reads) as f64 / (t_conflict.max(1) as f64 / 1e9),
+        conflict_ratio: t_conflict as f64 / t_hit.max(1) as f64,
+    }
+}
+
+// pick the fastest kernel that still causes row conflicts
+pub(crate) fn select_kernel(
+    a1: *const u8,
+    a2: *const u8,
+    h1: *const u8,
+    h2: *const u8,
+    num_reads: usize,
+) -> Option<KernelReport> {
+    let mut best: Option<KernelReport> = None;
+
+    for k in available_kernels() {
+        let report = measure_kernel(k, a1, a2, h1, h2, num_reads);
+        println!(
+            "{:?}: {:.0} activations/s, conflict ratio {:.2}",
+            report.kernel, report.activations_per_sec, report.conflict_ratio
+        );
+
+        if report.conflict_ratio < MIN_CONFLICT_RATIO {
+            continue;
+        }
+        match &best {
+            Some(b) if b.activations_per_sec >= report.activations_per_sec => {}
+            _ => best = Some(report),
+        }
+    }
+
+    best
+}
+
+pub(crate) fn reads_per_refresh(
+    kernel: HammerKernel,
+    a1: *const u8,
+    a2: *const u8,
+    refresh_period_us: usize,
+) -> usize {
+    let t = timer();
+
+    // double the granularity until one step takes longer than a refresh period
+    let mut gran = MIN_GRAN;
+    loop {
+        let t0 = t.now();
+        hammer_with(kernel, a1, a2, gran);
+        if t.elapsed_us(t0) >= refresh_period_us as u128 || gran >= MAX_GRAN {
+            break;
+        }
+        gran <<= 1;
+    }
+
+    let mut reads_per_hammer = 0;
     while gran > 0 {
-        let t0 = Instant::now();
-        hammer(a1, a2, reads_per_hammer + gran);
-        let t_diff = t0.elapsed().as_micros();
+        let t0 = t.now();
+        hammer_with(kernel, a1, a2, reads_per_hammer + gran);
+        let t_diff = t.elapsed_us(t0);
 
         if t_diff < refresh_period_us as u128 {
             //println!("too fast");
@@ -41,3 +255,134 @@ pub(crate) fn hammer(a1: *const u8, a2: *const u8, num_reads: usize) {
         }
     }
 }
+
+// hammer with the kernel from the config, synchronized to refresh commands if a cadence is set
+pub(crate) fn hammer_config(c: &Config, a1: Aggressor, a2: Aggressor, num_reads: usize) {
+    let (a1, a2) = (a1.as_ptr(), a2.as_ptr());
+    match &c.ref_sync {
+        Some(cadence) => hammer_synchronized(c.kernel, a1, a2, num_reads, cadence),
+        None => hammer_with(c.kernel, a1, a2, num_reads),
+    }
+}
+
+// hammer as configured and report the achieved activations
+pub(crate) fn hammer_report(c: &Config, a1: Aggressor, a2: Aggressor, num_reads: usize) -> HammerReport {
+    let t = timer();
+    let t0 = t.now();
+    hammer_config(c, a1, a2, num_reads);
+    HammerReport::new(c, num_reads, t.elapsed_us(t0))
+}
+
+pub(crate) fn hammer_with(kernel: HammerKernel, a1: *const u8, a2: *const u8, num_reads: usize) {
+    match kernel {
+        HammerKernel::Clflush => hammer(a1, a2, num_reads),
+        HammerKernel::ClflushMfence => unsafe {
+            for _ in 0..num_reads {
+                asm!("mov eax, [$0]\n\t\
+                      clflush [$0]\n\t\
+                      mov eax, [$1]\n\t\
+                      clflush [$1]\n\t\
+                      mfence"
+                      :
+                      : "r"(a1), "r"(a2)
+                      : "eax", "memory"
+                      : "volatile", "intel");
+            }
+        },
+        HammerKernel::ClflushLfence => unsafe {
+            for _ in 0..num_reads {
+                asm!("mov eax, [$0]\n\t\
+                      lfence\n\t\
+                      clflush [$0]\n\t\
+                      mov eax, [$1]\n\t\
+                      lfence\n\t\
+                      clflush [$1]"
+                      :
+                      : "r"(a1), "r"(a2)
+                      : "eax", "memory"
+                      : "volatile", "intel");
+            }
+        },
+        HammerKernel::ClflushoptMfence => unsafe {
+            for _ in 0..num_reads {
+                asm!("mov eax, [$0]\n\t\
+                      clflushopt [$0]\n\t\
+                      mov eax, [$1]\n\t\
+                      clflushopt [$1]\n\t\
+                      mfence"
+                      :
+                      : "r"(a1), "r"(a2)
+                      : "eax", "memory"
+                      : "volatile", "intel");
+            }
+        },
+        HammerKernel::MovntdqaClflush => unsafe {
+            for _ in 0..num_reads {
+                asm!("movntdqa xmm0, [$0]\n\t\
+                      clflush [$0]\n\t\
+                      movntdqa xmm0, [$1]\n\t\
+                      clflush [$1]"
+                      :
+                      : "r"(a1), "r"(a2)
+                      : "xmm0", "memory"
+                      : "volatile", "intel");
+            }
+        },
+        // overwrites the first 4 bytes of a1 and a2 with their current value
+        HammerKernel::MovntiMfence => unsafe {
+            for _ in 0..num_reads {
+                asm!("mov eax, [$0]\n\t\
+                      movnti [$0], eax\n\t\
+                      mov eax, [$1]\n\t\
+                      movnti [$1], eax\n\t\
+                      mfence"
+                      :
+                      : "r"(a1), "r"(a2)
+                      : "eax", "memory"
+                      : "volatile", "intel");
+            }
+        },
+        HammerKernel::Jit => hammer_jit(a1, a2, num_reads),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::config;
+
+    #[test]
+    fn report_within_one_window() {
+        let r = HammerReport::new(&config(64_000), 100_000, 30_000);
+        assert_eq!(r.requested_reads, 100_000);
+        assert_eq!(r.activations_per_refresh, 100_000);
+    }
+
+    #[test]
+    fn report_spread_over_windows() {
+        let r = HammerReport::new(&config(64_000), 400_000, 128_000);
+        assert_eq!(r.requested_reads, 400_000);
+        assert_eq!(r.activations_per_refresh, 200_000);
+    }
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn amount_from_args() {
+        let cases = [
+            ("template", Ok(None)),
+            ("template --reads 5000", Ok(Some(HammerAmount::Reads(5000)))),
+            ("parallel 4 --activations 300000", Ok(Some(HammerAmount::Activations(300000)))),
+            ("hcfirst --budget-us 64000", Ok(Some(HammerAmount::BudgetUs(64000)))),
+            ("template --reserve --windows 3", Ok(Some(HammerAmount::RefreshWindows(3)))),
+        ];
+        for (a, expected) in cases.iter() {
+            assert_eq!(&HammerAmount::from_args(&args(a)), expected, "{}", a);
+        }
+        assert!(HammerAmount::from_args(&args("template --reads")).is_err());
+        assert!(HammerAmount::from_args(&args("template --reads many")).is_err());
+        assert!(HammerAmount::from_args(&args("template --reads 1 --windows 2")).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+
+use crate::architecture::DramAddr;
+use crate::config::Config;
+use crate::memmap::{DramRange, MemMap};
+use crate::profile::{profile_ranges_reads, Flip};
+
+// hammer counts closer than this fraction of the upper bound end the search
+const HC_RESOLUTION: f64 = 1.0 / 64.0;
+// a hammer count flips a row if any of the repetitions flips a bit
+const HC_REPS: usize = 3;
+
+// minimum hammer count of a row
+// a read activates each aggressor once, so reads are activations of each aggressor
+#[derive(Clone, Debug)]
+pub(crate) struct RowHc {
+    pub row: DramAddr,
+    // NUMA node of the memory, rows of different nodes are on different DIMMs
+    pub node: Option<usize>,
+    // lowest searched read count that flipped any bit of the row
+    pub hc_first_reads: usize,
+    // flip.reads is the lowest read count of the row search the bit was seen at, there is
+    // no search per bit, so it is only an upper bound of the HC_first of the bit
+    pub flips: Vec<Flip>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct HcSummary {
+    pub rows: usize,
+    pub min: usize,
+    pub median: usize,
+    pub max: usize,
+}
+
+// hammer the row with num_reads reads, HC_REPS times with both data patterns
+fn row_flips(mem: &mut MemMap, da: &DramAddr, rs: &Vec<DramRange>, num_reads: usize, c: &Config) -> Vec<Flip> {
+    let row_above = mem.same_row_ranges(&da.row_above());
+    let row_below = mem.same_row_ranges(&da.row_below());
+
+    let mut flips = vec![];
+    for _ in 0..HC_REPS {
+        flips.append(&mut profile_ranges_reads(mem, &row_above, &row_below, rs, 0x00, num_reads, c));
+        flips.append(&mut profile_ranges_reads(mem, &row_above, &row_below, rs, 0xff, num_reads, c));
+    }
+    flips
+}
+
+// remember the lowest read count every bit flipped at
+fn record_flips(seen: &mut BTreeMap<DramAddr, Flip>, flips: Vec<Flip>) {
+    for f in flips {
+        match seen.get(&f.pos) {
+            Some(old) if old.reads <= f.reads => {}
+            _ => {
+                seen.insert(f.pos.clone(), f);
+            }
+        }
+    }
+}
+
+// binary search the minimum number of reads that flips a bit in the row
+// None if the row does not flip with max_reads
+pub(crate) fn hc_first_row(
+    mem: &mut MemMap,
+    da: &DramAddr,
+    rs: &Vec<DramRange>,
+    max_reads: usize,
+    c: &Config,
+) -> Option<RowHc> {
+    if da.row == 0 || da.row == u16::MAX {
+        return None;
+    }
+
+    let mut seen = BTreeMap::new();
+    let flips = row_flips(mem, da, rs, max_reads, c);
+    if flips.is_empty() {
+        return None;
+    }
+    record_flips(&mut seen, flips);
+
+    let mut lo = 0;
+    let mut hi = max_reads;
+    let min_step = ((max_reads as f64 * HC_RESOLUTION) as usize).max(1);
+    while hi - lo > min_step {
+        let mid = lo + (hi - lo) / 2;
+        let flips = row_flips(mem, da, rs, mid, c);
+        if flips.is_empty() {
+            lo = mid;
+        } else {
+            hi = mid;
+            record_flips(&mut seen, flips);
+        }
+    }
+
+    let flips: Vec<Flip> = seen.into_values().collect();
+    // hi is the lowest searched read count that flipped a bit
+    Some(RowHc {
+        row: da.row_aligned(),
+        node: mem.node(),
+        hc_first_reads: hi,
+        flips,
+    })
+}
+
+// HC_first of every vulnerable row in mem
+pub(crate) fn hc_first_ranges(mem: &mut MemMap, max_reads: usize, c: &Config) -> Vec<RowHc> {
+    let mut rows = vec![];
+    for da in mem.rows_with_both_neighbours() {
+        let rs = mem.same_row_ranges(&da);
+        if let Some(hc) = hc_first_row(mem, &da, &rs, max_reads, c) {
+            println!(
+                "(Chan {}, DIMM {}, Rank {}, Bank {}, Row {}): HC_first {} reads",
+                da.chan, da.dimm, da.rank, da.bank, da.row, hc.hc_first_reads
+            );
+            rows.push(hc);
+        }
+    }
+    rows
+}
+
+// distribution of HC_first grouped by key, e.g. per bank or per DIMM
+pub(crate) fn hc_distribution<K: Ord, F: Fn(&RowHc) -> K>(rows: &[RowHc], key: F) -> BTreeMap<K, HcSummary> {
+    let mut groups = BTreeMap::<K, Vec<usize>>::new();
+    for r in rows {
+        groups.entry(key(r)).or_default().push(r.hc_first_reads);
+    }
+
+    groups
+        .into_iter()
+        .map(|(k, mut hcs)| {
+            hcs.sort();
+            let summary = HcSummary {
+                rows: hcs.len(),
+                min: hcs[0],
+                median: hcs[hcs.len() / 2],
+                max: hcs[hcs.len() - 1],
+            };
+            (k, summary)
+        })
+        .collect()
+}
+
+// empty if the node is unknown
+fn node_csv(node: Option<usize>) -> String {
+    node.map_or(String::new(), |n| n.to_string())
+}
+
+// writes <prefix>_flips.csv, <prefix>_bank.csv and <prefix>_dimm.csv, hammer counts in reads
+// first_seen_reads of a flip is the upper bound described at RowHc::flips
+pub(crate) fn export_hc_csv(rows: &[RowHc], prefix: &str) -> io::Result<()> {
+    let mut f = File::create(format!("{}_flips.csv", prefix))?;
+    writeln!(f, "node,chan,dimm,rank,bank,row,col,byte,bit,dir,hc_first_reads_row,first_seen_reads")?;
+    for r in rows {
+        for flip in &r.flips {
+            let p = &flip.pos;
+            writeln!(
+                f,
+                "{},{},{},{},{},{},{},{},{},{:?},{},{}",
+                node_csv(flip.node), p.chan, p.dimm, p.rank, p.bank, p.row, p.col, p.byte, p.bit, flip.dir, r.hc_first_reads, flip.reads
+            )?;
+        }
+    }
+
+    let mut f = File::create(format!("{}_bank.csv", prefix))?;
+    writeln!(f, "node,chan,dimm,rank,bank,rows,min,median,max")?;
+    for ((node, b), s) in hc_distribution(rows, |r| (r.node, r.row.bank_key())) {
+        writeln!(
+            f,
+            "{},{},{},{},{},{},{},{},{}",
+            node_csv(node), b.chan, b.dimm, b.rank, b.bank, s.rows, s.min, s.median, s.max
+        )?;
+    }
+
+    let mut f = File::create(format!("{}_dimm.csv", prefix))?;
+    writeln!(f, "node,chan,dimm,rows,min,median,max")?;
+    for ((node, chan, dimm), s) in hc_distribution(rows, |r| (r.node, r.row.chan, r.row.dimm)) {
+        writeln!(f, "{},{},{},{},{},{},{}", node_csv(node), chan, dimm, s.rows, s.min, s.median, s.max)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row_hc(node: Option<usize>, row: u16, hc_first_reads: usize) -> RowHc {
+        RowHc {
+            row: DramAddr { chan: 0, dimm: 0, rank: 0, bank: 0, row, col: 0, byte: 0, bit: 0 },
+            node,
+            hc_first_reads,
+            flips: vec![],
+        }
+    }
+
+    #[test]
+    fn dimms_of_different_nodes_are_not_merged() {
+        let rows = vec![row_hc(Some(0), 1, 1000), row_hc(Some(0), 2, 3000), row_hc(Some(1), 1, 500)];
+        let dimms = hc_distribution(&rows, |r| (r.node, r.row.chan, r.row.dimm));
+        assert_eq!(dimms.len(), 2);
+        let node0 = &dimms[&(Some(0), 0, 0)];
+        assert_eq!((node0.rows, node0.min, node0.max), (2, 1000, 3000));
+        let node1 = &dimms[&(Some(1), 0, 0)];
+        assert_eq!((node1.rows, node1.min, node1.max), (1, 500, 500));
+    }
+}
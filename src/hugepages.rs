@@ -0,0 +1,355 @@
+use std::ffi::CString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use nix::libc;
+
+pub(crate) const NODE_ROOT: &str = "/sys/devices/system/node";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PageSize {
+    Mb2,
+    Gb1,
+}
+
+impl PageSize {
+    pub(crate) fn kb(self) -> usize {
+        match self {
+            PageSize::Mb2 => 2048,
+            PageSize::Gb1 => 1048576,
+        }
+    }
+
+    // "2M" / "1G", as used on the command line and by the hugetlbfs pagesize option
+    pub(crate) fn parse(s: &str) -> Option<PageSize> {
+        match s {
+            "2M" | "2m" | "2048kB" => Some(PageSize::Mb2),
+            "1G" | "1g" | "1048576kB" => Some(PageSize::Gb1),
+            _ => None,
+        }
+    }
+
+    fn mount_option(self) -> &'static str {
+        match self {
+            PageSize::Mb2 => "pagesize=2M",
+            PageSize::Gb1 => "pagesize=1G",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct PoolStatus {
+    pub node: usize,
+    pub size: PageSize,
+    pub nr: usize,
+    pub free: usize,
+    pub surplus: usize,
+}
+
+// per node hugetlb pools below a sysfs root, normally NODE_ROOT
+pub(crate) struct HugePages {
+    root: PathBuf,
+}
+
+impl Default for HugePages {
+    fn default() -> Self {
+        HugePages::new(NODE_ROOT)
+    }
+}
+
+impl HugePages {
+    pub(crate) fn new<P: AsRef<Path>>(root: P) -> Self {
+        HugePages {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn pool_dir(&self, node: usize, size: PageSize) -> PathBuf {
+        self.root
+            .join(format!("node{}", node))
+            .join("hugepages")
+            .join(format!("hugepages-{}kB", size.kb()))
+    }
+
+    fn read(&self, node: usize, size: PageSize, file: &str) -> Option<usize> {
+        fs::read_to_string(self.pool_dir(node, size).join(file))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    // NUMA nodes present below the root, sorted
+    pub(crate) fn nodes(&self) -> Vec<usize> {
+        let mut nodes: Vec<usize> = fs::read_dir(&self.root)
+            .map(|rd| {
+                rd.filter_map(|e| {
+                    let name = e.ok()?.file_name().into_string().ok()?;
+                    if !name.starts_with("node") {
+                        return None;
+                    }
+                    name[4..].parse().ok()
+                })
+                .collect()
+            })
+            .unwrap_or_default();
+        nodes.sort();
+        nodes
+    }
+
+    pub(crate) fn pool(&self, node: usize, size: PageSize) -> Option<PoolStatus> {
+        Some(PoolStatus {
+            node,
+            size,
+            nr: self.read(node, size, "nr_hugepages")?,
+            free: self.read(node, size, "free_hugepages")?,
+            surplus: self.read(node, size, "surplus_hugepages").unwrap_or(0),
+        })
+    }
+
+    // every pool of every node, pools the kernel does not support are left out
+    pub(crate) fn pools(&self) -> Vec<PoolStatus> {
+        let mut pools = vec![];
+        for node in self.nodes() {
+            for &size in &[PageSize::Mb2, PageSize::Gb1] {
+                if let Some(p) = self.pool(node, size) {
+                    pools.push(p);
+                }
+            }
+        }
+        pools
+    }
+
+    // set the pool size, the kernel may grant less than asked for, returns what it granted
+    pub(crate) fn set_nr(&self, node: usize, size: PageSize, nr: usize) -> Result<usize, String> {
+        let path = self.pool_dir(node, size).join("nr_hugepages");
+        fs::write(&path, format!("{}\n", nr)).map_err(|e| format!("writing {}: {}", path.display(), e))?;
+        self.read(node, size, "nr_hugepages")
+            .ok_or_else(|| format!("reading back {}", path.display()))
+    }
+
+    // make sure count pages are free on the node, grows the pool if needed
+    // the previous pool size is restored when the reservation is dropped
+    pub(crate) fn reserve(&self, node: usize, size: PageSize, count: usize) -> Result<Reservation, String> {
+        let pool = self
+            .pool(node, size)
+            .ok_or_else(|| format!("no {} kB pool on node {}", size.kb(), node))?;
+        let mut r = Reservation {
+            pools: HugePages::new(&self.root),
+            previous: vec![],
+            mount: None,
+        };
+        if pool.free >= count {
+            return Ok(r);
+        }
+
+        r.previous.push((node, size, pool.nr));
+        self.set_nr(node, size, pool.nr + count - pool.free)?;
+        let free = self.read(node, size, "free_hugepages").unwrap_or(0);
+        if free < count {
+            // r restores the old size when it goes out of scope
+            return Err(format!(
+                "node {} has only {} free {} kB pages, {} needed",
+                node,
+                free,
+                size.kb(),
+                count
+            ));
+        }
+        Ok(r)
+    }
+}
+
+// pool sizes changed for a run and an optional hugetlbfs mount, undone on drop
+pub(crate) struct Reservation {
+    pools: HugePages,
+    previous: Vec<(usize, PageSize, usize)>,
+    mount: Option<PathBuf>,
+}
+
+impl Reservation {
+    // mount hugetlbfs with the given page size, unmounted again on drop
+    pub(crate) fn mount<P: AsRef<Path>>(&mut self, target: P, size: PageSize) -> Result<(), String> {
+        let target = target.as_ref();
+        fs::create_dir_all(target).map_err(|e| format!("creating {}: {}", target.display(), e))?;
+        let c_target = CString::new(target.to_string_lossy().as_bytes()).map_err(|e| e.to_string())?;
+        let fstype = CString::new("hugetlbfs").unwrap();
+        let opts = CString::new(size.mount_option()).unwrap();
+        let ret = unsafe {
+            libc::mount(
+                fstype.as_ptr(),
+                c_target.as_ptr(),
+                fstype.as_ptr(),
+                0,
+                opts.as_ptr() as *const _,
+            )
+        };
+        if ret != 0 {
+            return Err(format!(
+                "mounting hugetlbfs on {}: {}",
+                target.display(),
+                std::io::Error::last_os_error()
+            ));
+        }
+        self.mount = Some(target.to_path_buf());
+        Ok(())
+    }
+
+    // keep the changes after the run, e.g. when reserving from the command line
+    pub(crate) fn keep(mut self) {
+        self.previous.clear();
+        self.mount = None;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Some(target) = self.mount.take() {
+            if let Ok(c_target) = CString::new(target.to_string_lossy().as_bytes()) {
+                unsafe {
+                    libc::umount(c_target.as_ptr());
+                }
+            }
+        }
+        for &(node, size, nr) in self.previous.iter().rev() {
+            if let Err(e) = self.pools.set_nr(node, size, nr) {
+                println!("Failed to restore hugepage pool: {}", e);
+            }
+        }
+    }
+}
+
+pub(crate) fn print_pools(pools: &[PoolStatus]) {
+    println!("node  size        total  free  surplus");
+    for p in pools {
+        println!(
+            "{:<5} {:<11} {:<6} {:<5} {}",
+            p.node,
+            format!("{} kB", p.size.kb()),
+            p.nr,
+            p.free,
+            p.surplus
+        );
+    }
+}
+
+// hugepages [--root <dir>] [status]
+// hugepages [--root <dir>] reserve <node> <2M|1G> <count> [--mount <dir>]
+pub(crate) fn hugepages_command(args: &[String]) -> Result<(), String> {
+    let mut args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    let mut pools = HugePages::default();
+    if args.first() == Some(&"--root") {
+        pools = HugePages::new(args.get(1).ok_or("--root needs a directory")?);
+        args.drain(..2);
+    }
+
+    match args.first() {
+        None | Some(&"status") if args.len() <= 1 => {
+            print_pools(&pools.pools());
+            Ok(())
+        }
+        Some(&"reserve") if args.len() == 4 || args.len() == 6 => {
+            let node = args[1].parse().map_err(|_| format!("invalid node {}", args[1]))?;
+            let size = PageSize::parse(args[2]).ok_or_else(|| format!("invalid page size {}", args[2]))?;
+            let count = args[3].parse().map_err(|_| format!("invalid count {}", args[3]))?;
+            let mut r = pools.reserve(node, size, count)?;
+            if args.len() == 6 {
+                if args[4] != "--mount" {
+                    return Err(format!("unexpected argument {}", args[4]));
+                }
+                r.mount(args[5], size)?;
+            }
+            r.keep();
+            print_pools(&pools.pools());
+            Ok(())
+        }
+        _ => Err(format!("unknown hugepages command {:?}", args)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // fake sysfs root with a 2 MB pool per (node, nr, free), removed on drop
+    struct FakeTree(PathBuf);
+
+    impl FakeTree {
+        fn new(name: &str, pools: &[(usize, usize, usize)]) -> Self {
+            let root = std::env::temp_dir().join(format!("rambleed-{}-{}", name, std::process::id()));
+            for &(node, nr, free) in pools {
+                let dir = root.join(format!("node{}/hugepages/hugepages-2048kB", node));
+                fs::create_dir_all(&dir).unwrap();
+                fs::write(dir.join("nr_hugepages"), format!("{}\n", nr)).unwrap();
+                fs::write(dir.join("free_hugepages"), format!("{}\n", free)).unwrap();
+            }
+            FakeTree(root)
+        }
+
+        fn nr(&self, node: usize) -> String {
+            fs::read_to_string(self.0.join(format!("node{}/hugepages/hugepages-2048kB/nr_hugepages", node))).unwrap()
+        }
+    }
+
+    impl Drop for FakeTree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn pools_of_fake_tree() {
+        let tree = FakeTree::new("pools", &[(1, 4, 3), (0, 2, 2)]);
+        let pools = HugePages::new(&tree.0);
+        assert_eq!(pools.nodes(), vec![0, 1]);
+        let p = pools.pools();
+        assert_eq!(p.len(), 2);
+        assert_eq!((p[1].node, p[1].size, p[1].nr, p[1].free, p[1].surplus), (1, PageSize::Mb2, 4, 3, 0));
+        assert!(pools.pool(0, PageSize::Gb1).is_none());
+    }
+
+    #[test]
+    fn reserve_with_free_pages_changes_nothing() {
+        let tree = FakeTree::new("free", &[(0, 2, 2)]);
+        let pools = HugePages::new(&tree.0);
+        let r = pools.reserve(0, PageSize::Mb2, 1).unwrap();
+        assert!(r.previous.is_empty());
+        drop(r);
+        assert_eq!(tree.nr(0), "2\n");
+    }
+
+    #[test]
+    fn failed_reserve_restores_pool() {
+        // the fake tree never frees pages, so growing the pool fails after writing nr_hugepages
+        let tree = FakeTree::new("failed", &[(0, 2, 0)]);
+        let pools = HugePages::new(&tree.0);
+        assert!(pools.reserve(0, PageSize::Mb2, 3).is_err());
+        assert_eq!(tree.nr(0), "2\n");
+        assert!(pools.reserve(1, PageSize::Mb2, 1).is_err());
+    }
+
+    #[test]
+    fn reservation_restores_pools_on_drop() {
+        let tree = FakeTree::new("drop", &[(0, 5, 5), (1, 7, 7)]);
+        let r = Reservation {
+            pools: HugePages::new(&tree.0),
+            previous: vec![(0, PageSize::Mb2, 1), (1, PageSize::Mb2, 2)],
+            mount: None,
+        };
+        drop(r);
+        assert_eq!(tree.nr(0), "1\n");
+        assert_eq!(tree.nr(1), "2\n");
+    }
+
+    #[test]
+    fn kept_reservation_is_not_restored() {
+        let tree = FakeTree::new("keep", &[(0, 5, 5)]);
+        let r = Reservation {
+            pools: HugePages::new(&tree.0),
+            previous: vec![(0, PageSize::Mb2, 1)],
+            mount: None,
+        };
+        r.keep();
+        assert_eq!(tree.nr(0), "5\n");
+    }
+}
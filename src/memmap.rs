@@ -1,20 +1,143 @@
-use crate::architecture::DramAddr;
+use crate::alloc::unmap;
+use crate::architecture::{BankKey, DramAddr, PhysAddr};
 use crate::config::Config;
+use crate::numa::node_of;
+use crate::phys::PhysResolver;
 use std::cmp::min;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
+use std::ptr;
 use std::slice;
 
+use vm_info::page_size;
+
 #[derive(Clone, Debug)]
 pub(crate) struct DramRange {
     pub start: DramAddr,
     pub bytes: usize,
 }
 
+// set operations work on the physical interval [phys, phys + bytes) a range covers
+// a range never crosses a row, so results stay within the row of the inputs
+impl DramRange {
+    pub(crate) fn phys(&self, c: &Config) -> PhysAddr {
+        c.arch.dram_to_phys(&self.start)
+    }
+
+    pub(crate) fn phys_end(&self, c: &Config) -> PhysAddr {
+        self.phys(c) + self.bytes
+    }
+
+    // None for an empty interval
+    pub(crate) fn from_phys(start: PhysAddr, end: PhysAddr, c: &Config) -> Option<DramRange> {
+        if end <= start {
+            return None;
+        }
+        Some(DramRange {
+            start: c.arch.phys_to_dram(start),
+            bytes: end - start,
+        })
+    }
+
+    // the parts before and from at
+    pub(crate) fn split_at(&self, at: PhysAddr, c: &Config) -> (Option<DramRange>, Option<DramRange>) {
+        let (start, end) = (self.phys(c), self.phys_end(c));
+        let at = at.max(start).min(end);
+        (DramRange::from_phys(start, at, c), DramRange::from_phys(at, end, c))
+    }
+
+    pub(crate) fn intersect(&self, other: &DramRange, c: &Config) -> Option<DramRange> {
+        DramRange::from_phys(self.phys(c).max(other.phys(c)), self.phys_end(c).min(other.phys_end(c)), c)
+    }
+
+    // what is left of self without other, up to two pieces
+    pub(crate) fn subtract(&self, other: &DramRange, c: &Config) -> Vec<DramRange> {
+        if self.intersect(other, c).is_none() {
+            return vec![self.clone()];
+        }
+        let (before, _) = self.split_at(other.phys(c), c);
+        let (_, after) = self.split_at(other.phys_end(c), c);
+        before.into_iter().chain(after).collect()
+    }
+
+    // one range covering both, if they overlap or touch
+    pub(crate) fn merge(&self, other: &DramRange, c: &Config) -> Option<DramRange> {
+        let (s1, e1, s2, e2) = (self.phys(c), self.phys_end(c), other.phys(c), other.phys_end(c));
+        if s1 > e2 || s2 > e1 {
+            return None;
+        }
+        DramRange::from_phys(s1.min(s2), e1.max(e2), c)
+    }
+}
+
+// every range of rs without the parts covered by cut
+pub(crate) fn subtract_ranges(rs: &[DramRange], cut: &[DramRange], c: &Config) -> Vec<DramRange> {
+    let mut left = rs.to_vec();
+    for x in cut {
+        left = left.iter().flat_map(|r| r.subtract(x, c)).collect();
+    }
+    left
+}
+
+// the parts of rs also covered by other
+pub(crate) fn intersect_ranges(rs: &[DramRange], other: &[DramRange], c: &Config) -> Vec<DramRange> {
+    rs.iter()
+        .flat_map(|r| other.iter().filter_map(move |o| r.intersect(o, c)))
+        .collect()
+}
+
+// sorted by physical address with overlapping and touching ranges joined
+// only for ranges that are contiguous virtually as well, e.g. pieces of one mapped range
+pub(crate) fn merge_ranges(rs: &[DramRange], c: &Config) -> Vec<DramRange> {
+    let mut sorted = rs.to_vec();
+    sorted.sort_by_key(|r| r.phys(c));
+    let mut merged: Vec<DramRange> = Vec::with_capacity(sorted.len());
+    for r in sorted {
+        let joined = merged.last().and_then(|last| last.merge(&r, c));
+        match joined {
+            Some(m) => *merged.last_mut().unwrap() = m,
+            None => merged.push(r),
+        }
+    }
+    merged
+}
+
+// mapped ranges of every row, ordered by bank and row
+pub(crate) type RowIndex = BTreeMap<BankKey, BTreeMap<u16, Vec<DramRange>>>;
+
+// an address inside a MemMap to hammer or time, it cannot outlive the mapping
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Aggressor<'a> {
+    ptr: *const u8,
+    map: PhantomData<&'a MemMap>,
+}
+
+impl<'a> Aggressor<'a> {
+    pub fn as_ptr(self) -> *const u8 {
+        self.ptr
+    }
+}
+
+// a buffer of pages, each translated to its physical frame, which need not be contiguous
 pub(crate) struct MemMap {
     buf: *mut u8,
     len: usize,
-    range_map: HashMap<DramAddr, Vec<DramRange>>,
+    // unmapped on drop, views into a mapping owned elsewhere are not
+    owned: bool,
+    page_size: usize,
+    // physical address of every page, None if the resolver does not know it
+    frames: Vec<Option<PhysAddr>>,
+    // physical page number -> page index in buf
+    page_of_frame: HashMap<usize, usize>,
+    // low bits of the physical addresses that are known
+    phys_bits: usize,
+    resolver: &'static str,
+    // NUMA node of the first page
+    node: Option<usize>,
+    index: RowIndex,
+    // ranges taken out of the index, e.g. the secret of a victim
+    excluded: RowIndex,
 }
 
 impl Deref for MemMap {
@@ -31,76 +154,469 @@ impl DerefMut for MemMap {
     }
 }
 
+// a shared MemMap only hands out shared slices, writing through &MemMap is left to the
+// unsafe write_byte, so sharing it between threads is sound
+unsafe impl Sync for MemMap {}
+
+impl Drop for MemMap {
+    fn drop(&mut self) {
+        if self.owned {
+            unmap(self.buf, self.len);
+        }
+    }
+}
+
 impl MemMap {
-    pub(crate) fn new(buf: *mut u8, len: usize, c : &Config) -> Self {
+    // takes ownership of the mapping buf[..len] and unmaps it on drop
+    // buf has to come from mmap and must not be unmapped elsewhere
+    pub(crate) unsafe fn new(buf: *mut u8, len: usize, resolver: &dyn PhysResolver, c: &Config) -> Self {
+        MemMap::translate(buf, len, page_size().unwrap_or(4096), true, resolver, c)
+    }
+
+    // like new, but translates whole pages of page_size, e.g. a 1 GB page with a single pagemap read
+    // buf[..len] has to be physically contiguous within each page
+    pub(crate) unsafe fn with_page_size(
+        buf: *mut u8,
+        len: usize,
+        page_size: usize,
+        resolver: &dyn PhysResolver,
+        c: &Config,
+    ) -> Self {
+        MemMap::translate(buf, len, page_size, true, resolver, c)
+    }
+
+    // buf[..len] stays owned by the caller, who keeps it mapped for as long as the view lives
+    pub(crate) unsafe fn view(buf: *mut u8, len: usize, resolver: &dyn PhysResolver, c: &Config) -> Self {
+        MemMap::translate(buf, len, page_size().unwrap_or(4096), false, resolver, c)
+    }
+
+    fn translate(buf: *mut u8, len: usize, page_size: usize, owned: bool, resolver: &dyn PhysResolver, c: &Config) -> Self {
+        let pages = len.div_ceil(page_size);
+
+        // without a translation of buf only offsets within the buffer are known
+        let (frames, phys_bits): (Vec<Option<PhysAddr>>, usize) = if resolver.resolve(buf).is_some() {
+            let frames = (0..pages)
+                .map(|i| resolver.resolve(buf.wrapping_add(i * page_size)))
+                .collect();
+            (frames, resolver.known_bits())
+        } else {
+            ((0..pages).map(|i| Some(i * page_size)).collect(), 0)
+        };
+
+        let page_of_frame = frames
+            .iter()
+            .enumerate()
+            .filter_map(|(i, f)| Some((f.as_ref()? / page_size, i)))
+            .collect();
+        let index = to_index(&frames, page_size, len, c);
         MemMap {
             buf,
             len,
-            range_map: to_range_map(len, c),
+            owned,
+            page_size,
+            frames,
+            page_of_frame,
+            phys_bits,
+            resolver: resolver.name(),
+            node: node_of(buf),
+            index,
+            excluded: RowIndex::new(),
+        }
+    }
+
+    pub fn node(&self) -> Option<usize> {
+        self.node
+    }
+
+    // pages the resolver could not translate are left out of the index
+    pub fn unresolved_pages(&self) -> usize {
+        self.frames.iter().filter(|f| f.is_none()).count()
+    }
+
+    pub fn offset_to_phys(&self, offset: usize) -> Option<PhysAddr> {
+        Some((*self.frames.get(offset / self.page_size)?)? + offset % self.page_size)
+    }
+
+    pub fn phys_to_offset(&self, p: PhysAddr) -> Option<usize> {
+        let page = self.page_of_frame.get(&(p / self.page_size))?;
+        let off = page * self.page_size + p % self.page_size;
+        if off < self.len {
+            Some(off)
+        } else {
+            None
+        }
+    }
+
+    // "0x1234 (pagemap)", "0x1234 (low 21 bits, huge page relative)" or "unknown"
+    pub fn describe_phys(&self, offset: usize) -> String {
+        match (self.phys_bits, self.offset_to_phys(offset)) {
+            (0, _) | (_, None) => String::from("unknown"),
+            (b, Some(p)) if b >= 64 => format!("{:#x} ({})", p, self.resolver),
+            (b, Some(p)) => format!("{:#x} (low {} bits, {})", p, b, self.resolver),
+        }
+    }
+
+    // take r out of its row, it is neither hammered nor checked for flips afterwards
+    pub fn remove_range(&mut self, r: &DramRange, c: &Config) {
+        let bank = r.start.bank_key();
+        let row = r.start.row;
+        let rows = match self.index.get_mut(&bank) {
+            Some(rows) => rows,
+            None => return,
+        };
+        let old = match rows.remove(&row) {
+            Some(old) => old,
+            None => return,
+        };
+
+        let removed = intersect_ranges(&old, std::slice::from_ref(r), c);
+        let left = subtract_ranges(&old, std::slice::from_ref(r), c);
+        if !left.is_empty() {
+            rows.insert(row, left);
+        }
+        if removed.is_empty() {
+            return;
+        }
+        let ex = self.excluded.entry(bank).or_default().entry(row).or_default();
+        ex.extend(removed);
+        *ex = merge_ranges(ex, c);
+    }
+
+    // exclude buf[offset..offset + len], e.g. pages handed to a victim
+    pub fn exclude(&mut self, offset: usize, len: usize, c: &Config) {
+        let end = min(offset + len, self.len);
+        let chunk = min(c.contiguous_dram_addr, self.page_size);
+        let mut i = offset;
+        while i < end {
+            // up to the next chunk boundary, chunks never cross a row
+            let next = min((i / chunk + 1) * chunk, end);
+            if let Some(r) = self.offset_to_phys(i).and_then(|p| DramRange::from_phys(p, p + next - i, c)) {
+                self.remove_range(&r, c);
+            }
+            i = next;
         }
     }
 
-    pub fn remove_range(&mut self, _: &DramRange) {
-        //TODO
+    // parts of the row of da taken out with remove_range or exclude
+    pub fn excluded_ranges(&self, da: &DramAddr) -> &[DramRange] {
+        self.excluded
+            .get(&da.bank_key())
+            .and_then(|rows| rows.get(&da.row))
+            .map_or(&[], |rs| rs.as_slice())
+    }
+
+    // ranges of the row of da still available for hammering, empty if the row is not mapped
+    pub fn row_ranges(&self, da: &DramAddr) -> &[DramRange] {
+        self.index
+            .get(&da.bank_key())
+            .and_then(|rows| rows.get(&da.row))
+            .map_or(&[], |rs| rs.as_slice())
     }
 
     pub fn same_row_ranges(&self, da: &DramAddr) -> Vec<DramRange> {
-        self.range_map
-            .get(&da.row_aligned())
-            .unwrap_or(&vec![])
-            .clone()
+        self.row_ranges(da).to_vec()
+    }
+
+    pub fn index(&self) -> &RowIndex {
+        &self.index
     }
 
-    pub fn get_ranges(&self) -> &HashMap<DramAddr, Vec<DramRange>> {
-        &self.range_map
+    // row aligned addresses of all mapped rows, ordered by bank and row
+    pub fn rows(&self) -> Vec<DramAddr> {
+        self.index
+            .iter()
+            .flat_map(|(b, rows)| rows.keys().map(move |&r| b.row(r)))
+            .collect()
     }
 
-    pub fn offset(&self, n: usize) -> *mut u8 {
-        self.buf.wrapping_add(n)
+    // rows of the bank whose direct neighbours above and below are mapped as well, row aligned
+    pub fn rows_with_neighbours(&self, bank: &BankKey) -> Vec<DramAddr> {
+        let rows = match self.index.get(bank) {
+            Some(rows) => rows,
+            None => return vec![],
+        };
+        rows.keys()
+            .filter(|&&r| {
+                r.checked_sub(1).is_some_and(|n| rows.contains_key(&n))
+                    && r.checked_add(1).is_some_and(|n| rows.contains_key(&n))
+            })
+            .map(|&r| bank.row(r))
+            .collect()
     }
 
-    pub fn at_dram(&mut self, da : &DramAddr, c : &Config) -> &mut u8 {
-        &mut self[c.arch.dram_to_phys(&da)]
+    // rows of all banks that can be hammered double-sided, ordered by bank and row
+    pub fn rows_with_both_neighbours(&self) -> Vec<DramAddr> {
+        self.index.keys().flat_map(|b| self.rows_with_neighbours(b)).collect()
     }
 
-    pub fn dram_to_offset(&self, da : &DramAddr, c : &Config) -> usize {
-        c.arch.dram_to_phys(&da)
+    // mapped addresses in two distinct rows of the same bank, as far apart as possible
+    pub fn same_bank_pair(&self, bank: &BankKey) -> Option<(DramAddr, DramAddr)> {
+        let rows = self.index.get(bank)?;
+        let (first, r1) = rows.iter().next()?;
+        let (last, r2) = rows.iter().next_back()?;
+        if first == last {
+            return None;
+        }
+        Some((r1.first()?.start.clone(), r2.first()?.start.clone()))
     }
 
-    pub fn offset_to_dram(&self, offset : usize, c : &Config) -> DramAddr {
-        c.arch.phys_to_dram(offset)
+    // a row conflict pair from the first bank with at least two mapped rows
+    pub fn any_same_bank_pair(&self) -> Option<(DramAddr, DramAddr)> {
+        self.index.keys().filter_map(|b| self.same_bank_pair(b)).next()
     }
 
-    pub fn dram_to_virt(&self, da : &DramAddr, c : &Config) -> *mut u8 {
-        self.buf.wrapping_add(c.arch.dram_to_phys(&da))
+    // None for offsets past the end of the buffer
+    pub fn at_offset(&self, n: usize) -> Option<Aggressor<'_>> {
+        if n >= self.len {
+            return None;
+        }
+        Some(Aggressor {
+            ptr: self.buf.wrapping_add(n),
+            map: PhantomData,
+        })
+    }
+
+    pub fn aggressor(&self, da: &DramAddr, c: &Config) -> Option<Aggressor<'_>> {
+        self.at_offset(self.dram_to_offset(da, c)?)
+    }
+
+    pub fn aggressor_pair(&self, a1: &DramAddr, a2: &DramAddr, c: &Config) -> Option<(Aggressor<'_>, Aggressor<'_>)> {
+        Some((self.aggressor(a1, c)?, self.aggressor(a2, c)?))
     }
-}
 
-pub(crate) fn offset_to_dram(offset: usize, c: &Config) -> DramAddr {
-    //let phys = virt_to_phys_aligned(offset, c.aligned_bits);
-    c.arch.phys_to_dram(offset)
+    // byte access through the raw mapping instead of a slice of it, for threads sharing a map
+    // safety: while this runs, no reference to the byte at offset may be alive (from Deref,
+    // DerefMut, get_dram, ...) and no other thread may access that byte
+    pub unsafe fn read_byte(&self, offset: usize) -> Option<u8> {
+        if offset >= self.len {
+            return None;
+        }
+        Some(ptr::read_volatile(self.buf.wrapping_add(offset)))
+    }
+
+    // safety: as for read_byte
+    pub unsafe fn write_byte(&self, offset: usize, b: u8) -> bool {
+        if offset >= self.len {
+            return false;
+        }
+        ptr::write_volatile(self.buf.wrapping_add(offset), b);
+        true
+    }
+
+    pub fn get_dram(&self, da: &DramAddr, c: &Config) -> Option<&u8> {
+        let off = self.dram_to_offset(da, c)?;
+        self.get(off)
+    }
+
+    pub fn get_dram_mut(&mut self, da: &DramAddr, c: &Config) -> Option<&mut u8> {
+        let off = self.dram_to_offset(da, c)?;
+        self.get_mut(off)
+    }
+
+    pub fn dram_to_offset(&self, da : &DramAddr, c : &Config) -> Option<usize> {
+        self.phys_to_offset(c.arch.dram_to_phys(da))
+    }
+
+    pub fn offset_to_dram(&self, offset : usize, c : &Config) -> Option<DramAddr> {
+        Some(c.arch.phys_to_dram(self.offset_to_phys(offset)?))
+    }
 }
-// assumes mem is aligned to contiguous address range
-fn split_into_ranges(len: usize, c: &Config) -> Vec<DramRange> {
-    let mut ranges = Vec::new();
-    for i in (0..len).step_by(c.contiguous_dram_addr) {
-        ranges.push(DramRange {
-            start: offset_to_dram(i, c),
-            bytes: min(len - i, c.contiguous_dram_addr),
-        });
+
+// chunks of contiguous_dram_addr bytes, translated page by page
+// adjacent chunks in the same row are merged if they are adjacent physically as well
+fn split_into_ranges(frames: &[Option<PhysAddr>], page_size: usize, len: usize, c: &Config) -> Vec<DramRange> {
+    let chunk = min(c.contiguous_dram_addr, page_size);
+    let mut ranges: Vec<DramRange> = Vec::new();
+    // physical address right after the last range
+    let mut last_end = None;
+    for i in (0..len).step_by(chunk) {
+        let phys = match frames[i / page_size] {
+            Some(f) => f + i % page_size,
+            None => {
+                last_end = None;
+                continue;
+            }
+        };
+        let r = DramRange {
+            start: c.arch.phys_to_dram(phys),
+            bytes: min(len - i, chunk),
+        };
+        let end = phys + r.bytes;
+        match ranges.last_mut() {
+            Some(last) if last_end == Some(phys) && last.start.row_aligned() == r.start.row_aligned() => {
+                last.bytes += r.bytes
+            }
+            _ => ranges.push(r),
+        }
+        last_end = Some(end);
     }
 
     ranges
 }
 
-fn to_range_map(len: usize, c: &Config) -> HashMap<DramAddr, Vec<DramRange>> {
-    let mut range_map = HashMap::<DramAddr, Vec<DramRange>>::new();
-    for r in split_into_ranges(len, c) {
-        range_map
-            .entry(r.start.row_aligned())
-            .or_insert_with(Vec::new)
+fn to_index(frames: &[Option<PhysAddr>], page_size: usize, len: usize, c: &Config) -> RowIndex {
+    let mut index = RowIndex::new();
+    for r in split_into_ranges(frames, page_size, len, c) {
+        index
+            .entry(r.start.bank_key())
+            .or_default()
+            .entry(r.start.row)
+            .or_default()
             .push(r);
     }
-    //println!("{:#?}", range_map);
-    range_map
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::map_aligned;
+    use crate::phys::HugePageRelative;
+    use crate::testutil::config;
+    use nix::libc;
+
+    const BITS: usize = 22;
+    const LEN: usize = 1 << BITS;
+
+    #[test]
+    fn first_and_last_rows_have_no_neighbours() {
+        let c = config(64_000);
+        let bank = BankKey { chan: 0, dimm: 0, rank: 0, bank: 0 };
+        let mut rows = BTreeMap::new();
+        for &r in &[0, 1, 2, u16::MAX - 1, u16::MAX] {
+            rows.insert(r, vec![DramRange { start: bank.row(r), bytes: 64 }]);
+        }
+        let mut mem = unsafe { MemMap::view(ptr::null_mut(), 0, &HugePageRelative { bits: BITS }, &c) };
+        mem.index.insert(bank, rows);
+
+        let inner: Vec<u16> = mem.rows_with_neighbours(&bank).iter().map(|da| da.row).collect();
+        assert_eq!(inner, vec![1]);
+    }
+
+    #[test]
+    fn huge_pages_index_like_small_pages() {
+        let c = config(64_000);
+        let buf = map_aligned(LEN, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE).unwrap();
+        let resolver = HugePageRelative { bits: BITS };
+        let huge = unsafe { MemMap::with_page_size(buf, LEN, LEN, &resolver, &c) };
+        let small = unsafe { MemMap::view(buf, LEN, &resolver, &c) };
+
+        assert_eq!(huge.frames.len(), 1);
+        assert_eq!(huge.rows(), small.rows());
+        for da in huge.rows() {
+            let bytes = |m: &MemMap| m.row_ranges(&da).iter().map(|r| r.bytes).sum::<usize>();
+            assert_eq!(bytes(&huge), bytes(&small));
+        }
+        let da = huge.offset_to_dram(LEN / 2 + 64, &c).unwrap();
+        assert_eq!(huge.dram_to_offset(&da, &c), Some(LEN / 2 + 64));
+    }
+
+    // ranges of the first 4 KB, which lie in one row, as physical intervals
+    fn r(start: PhysAddr, end: PhysAddr, c: &Config) -> DramRange {
+        DramRange::from_phys(start, end, c).unwrap()
+    }
+
+    // start and end physical address of a range
+    type Bounds = (PhysAddr, PhysAddr);
+
+    fn bounds(rs: &[DramRange], c: &Config) -> Vec<Bounds> {
+        rs.iter().map(|r| (r.phys(c), r.phys_end(c))).collect()
+    }
+
+    #[test]
+    fn split_at_clamps_to_the_range() {
+        let c = config(64_000);
+        let cases: &[(PhysAddr, Option<Bounds>, Option<Bounds>)] = &[
+            (0x100, Some((0x40, 0x100)), Some((0x100, 0x200))),
+            (0x40, None, Some((0x40, 0x200))),
+            (0x200, Some((0x40, 0x200)), None),
+            (0x0, None, Some((0x40, 0x200))),
+            (0x1000, Some((0x40, 0x200)), None),
+        ];
+        for &(at, before, after) in cases {
+            let (b, a) = r(0x40, 0x200, &c).split_at(at, &c);
+            assert_eq!(b.map(|b| (b.phys(&c), b.phys_end(&c))), before, "at {:#x}", at);
+            assert_eq!(a.map(|a| (a.phys(&c), a.phys_end(&c))), after, "at {:#x}", at);
+        }
+    }
+
+    #[test]
+    fn subtract_cuts_up_to_two_pieces() {
+        let c = config(64_000);
+        let cases: &[(Bounds, Vec<Bounds>)] = &[
+            // disjoint and adjacent cuts leave the range alone
+            ((0x400, 0x500), vec![(0x100, 0x200)]),
+            ((0x200, 0x300), vec![(0x100, 0x200)]),
+            ((0x0, 0x100), vec![(0x100, 0x200)]),
+            // boundary cuts
+            ((0x0, 0x140), vec![(0x140, 0x200)]),
+            ((0x1c0, 0x300), vec![(0x100, 0x1c0)]),
+            // a hole in the middle
+            ((0x140, 0x180), vec![(0x100, 0x140), (0x180, 0x200)]),
+            // covering everything
+            ((0x100, 0x200), vec![]),
+            ((0x0, 0x1000), vec![]),
+        ];
+        for ((s, e), expected) in cases {
+            let left = r(0x100, 0x200, &c).subtract(&r(*s, *e, &c), &c);
+            assert_eq!(&bounds(&left, &c), expected, "cut {:#x}..{:#x}", s, e);
+        }
+    }
+
+    #[test]
+    fn intersect_and_merge() {
+        let c = config(64_000);
+        let cases: &[(Bounds, Option<Bounds>, Option<Bounds>)] = &[
+            // other, intersection, merge
+            ((0x300, 0x400), None, None),
+            ((0x200, 0x300), None, Some((0x100, 0x300))),
+            ((0x180, 0x300), Some((0x180, 0x200)), Some((0x100, 0x300))),
+            ((0x140, 0x180), Some((0x140, 0x180)), Some((0x100, 0x200))),
+            ((0x0, 0x1000), Some((0x100, 0x200)), Some((0x0, 0x1000))),
+        ];
+        let a = r(0x100, 0x200, &c);
+        for &((s, e), inter, merged) in cases {
+            let b = r(s, e, &c);
+            let pair = |x: Option<DramRange>| x.map(|x| (x.phys(&c), x.phys_end(&c)));
+            assert_eq!(pair(a.intersect(&b, &c)), inter, "{:#x}..{:#x}", s, e);
+            assert_eq!(pair(b.intersect(&a, &c)), inter, "{:#x}..{:#x}", s, e);
+            assert_eq!(pair(a.merge(&b, &c)), merged, "{:#x}..{:#x}", s, e);
+            assert_eq!(pair(b.merge(&a, &c)), merged, "{:#x}..{:#x}", s, e);
+        }
+    }
+
+    #[test]
+    fn range_lists() {
+        let c = config(64_000);
+        let rs = vec![r(0x300, 0x400, &c), r(0x0, 0x100, &c), r(0x100, 0x180, &c), r(0x160, 0x200, &c)];
+        assert_eq!(bounds(&merge_ranges(&rs, &c), &c), vec![(0x0, 0x200), (0x300, 0x400)]);
+        assert!(merge_ranges(&[], &c).is_empty());
+
+        let cut = vec![r(0x80, 0x340, &c)];
+        assert_eq!(
+            bounds(&subtract_ranges(&merge_ranges(&rs, &c), &cut, &c), &c),
+            vec![(0x0, 0x80), (0x340, 0x400)]
+        );
+        assert!(subtract_ranges(&rs, &[r(0x0, 0x1000, &c)], &c).is_empty());
+        assert_eq!(
+            bounds(&intersect_ranges(&merge_ranges(&rs, &c), &cut, &c), &c),
+            vec![(0x80, 0x200), (0x300, 0x340)]
+        );
+    }
+
+    #[test]
+    fn excluded_bytes_leave_the_row() {
+        let c = config(64_000);
+        let buf = map_aligned(LEN, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE).unwrap();
+        let mut mem = unsafe { MemMap::with_page_size(buf, LEN, LEN, &HugePageRelative { bits: BITS }, &c) };
+        let da = mem.offset_to_dram(0x100, &c).unwrap();
+        let row_bytes = |m: &MemMap| m.row_ranges(&da).iter().map(|r| r.bytes).sum::<usize>();
+        let before = row_bytes(&mem);
+
+        mem.exclude(0x100, 0x40, &c);
+        mem.exclude(0x140, 0x40, &c);
+        assert_eq!(row_bytes(&mem), before - 0x80);
+        assert_eq!(bounds(mem.excluded_ranges(&da), &c), vec![(0x100, 0x180)]);
+        assert!(mem.row_ranges(&da).iter().all(|r| r.intersect(&mem.excluded_ranges(&da)[0], &c).is_none()));
+    }
 }
@@ -0,0 +1,222 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::ptr::null;
+
+use nix::libc;
+
+use crate::alloc::{AllocError, Allocation, AllocationStrategy};
+use crate::config::Config;
+use crate::hugepages::NODE_ROOT;
+use crate::runenv::parse_cpu_list;
+
+const MPOL_DEFAULT: i32 = 0;
+const MPOL_BIND: i32 = 2;
+// get_mempolicy flags: return the node of the page at addr instead of the policy
+const MPOL_F_NODE: usize = 1;
+const MPOL_F_ADDR: usize = 2;
+// largest node count the kernel can be configured for
+const MAX_NODES: usize = 1024;
+const MASK_BITS: usize = 64;
+
+type NodeMask = [u64; MAX_NODES / MASK_BITS];
+
+fn get_mempolicy(mode: &mut i32, mask: &mut NodeMask) -> io::Result<()> {
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_get_mempolicy,
+            mode as *mut i32,
+            mask.as_mut_ptr(),
+            MAX_NODES,
+            null::<u8>(),
+            0,
+        )
+    };
+    if r != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn set_mempolicy(mode: i32, mask: &NodeMask) -> io::Result<()> {
+    // the kernel drops the last bit of maxnode
+    let r = unsafe { libc::syscall(libc::SYS_set_mempolicy, mode, mask.as_ptr(), MAX_NODES + 1) };
+    if r != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn mask_nodes(mask: &NodeMask) -> Vec<usize> {
+    (0..MAX_NODES)
+        .filter(|&n| mask[n / MASK_BITS] & (1 << (n % MASK_BITS)) != 0)
+        .collect()
+}
+
+fn node_mask(node: usize) -> NodeMask {
+    let mut mask = [0; MAX_NODES / MASK_BITS];
+    mask[node / MASK_BITS] |= 1 << (node % MASK_BITS);
+    mask
+}
+
+// the node of a policy that binds to exactly one node
+fn single_bound_node(mode: i32, mask: &NodeMask) -> Option<usize> {
+    if mode != MPOL_BIND {
+        return None;
+    }
+    match mask_nodes(mask).as_slice() {
+        &[node] => Some(node),
+        _ => None,
+    }
+}
+
+// node of the page at v, faults the page in if it is not mapped yet
+pub(crate) fn node_of(v: *const u8) -> Option<usize> {
+    let mut node: i32 = -1;
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_get_mempolicy,
+            &mut node as *mut i32,
+            null::<u64>(),
+            0,
+            v,
+            MPOL_F_NODE | MPOL_F_ADDR,
+        )
+    };
+    if r != 0 || node < 0 {
+        return None;
+    }
+    Some(node as usize)
+}
+
+// the single node the allocations of this thread are bound to, if any
+pub(crate) fn bound_node() -> Option<usize> {
+    let mut mode = 0;
+    let mut mask = [0; MAX_NODES / MASK_BITS];
+    get_mempolicy(&mut mode, &mut mask).ok()?;
+    single_bound_node(mode, &mask)
+}
+
+pub(crate) fn node_cpus(node: usize) -> Vec<usize> {
+    node_cpus_below(NODE_ROOT, node)
+}
+
+// cpus of node from its cpulist below a sysfs root, normally NODE_ROOT
+fn node_cpus_below<P: AsRef<Path>>(root: P, node: usize) -> Vec<usize> {
+    fs::read_to_string(root.as_ref().join(format!("node{}/cpulist", node)))
+        .map(|s| parse_cpu_list(&s))
+        .unwrap_or_default()
+}
+
+// binds all memory allocations of the calling thread to one node, the previous policy is restored on drop
+pub(crate) struct NodeBinding {
+    previous: (i32, NodeMask),
+}
+
+impl NodeBinding {
+    pub(crate) fn bind(node: usize) -> Result<Self, String> {
+        if node >= MAX_NODES {
+            return Err(format!("node {} is out of range", node));
+        }
+        let mut previous = (MPOL_DEFAULT, [0; MAX_NODES / MASK_BITS]);
+        get_mempolicy(&mut previous.0, &mut previous.1).map_err(|e| format!("get_mempolicy: {}", e))?;
+
+        set_mempolicy(MPOL_BIND, &node_mask(node)).map_err(|e| format!("set_mempolicy on node {}: {}", node, e))?;
+        Ok(NodeBinding { previous })
+    }
+}
+
+impl Drop for NodeBinding {
+    fn drop(&mut self) {
+        if let Err(e) = set_mempolicy(self.previous.0, &self.previous.1) {
+            println!("Failed to restore the memory policy: {}", e);
+        }
+    }
+}
+
+// allocate with strategy while bound to node and check where the memory landed
+pub(crate) struct OnNode {
+    pub node: usize,
+    pub strategy: Box<dyn AllocationStrategy>,
+}
+
+impl AllocationStrategy for OnNode {
+    fn name(&self) -> &'static str {
+        self.strategy.name()
+    }
+
+    fn allocate(&self, c: &Config) -> Result<Allocation, AllocError> {
+        let binding = NodeBinding::bind(self.node).map_err(AllocError::Numa)?;
+        let allocation = self.strategy.allocate(c);
+        drop(binding);
+
+        let allocation = allocation?;
+        match allocation.mem.node() {
+            Some(n) if n != self.node => Err(AllocError::WrongNode { wanted: self.node, node: n }),
+            _ => Ok(allocation),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hugepages::HugePages;
+
+    #[test]
+    fn node_masks() {
+        for &node in &[0, 1, 63, 64, 130, MAX_NODES - 1] {
+            assert_eq!(mask_nodes(&node_mask(node)), vec![node]);
+        }
+        let mut mask = node_mask(2);
+        mask[1] |= 1 << 3;
+        assert_eq!(mask_nodes(&mask), vec![2, 67]);
+        assert!(mask_nodes(&[0; MAX_NODES / MASK_BITS]).is_empty());
+    }
+
+    #[test]
+    fn only_bind_to_one_node_is_bound() {
+        let mut two = node_mask(0);
+        two[0] |= 1 << 1;
+        assert_eq!(single_bound_node(MPOL_BIND, &node_mask(1)), Some(1));
+        assert_eq!(single_bound_node(MPOL_BIND, &two), None);
+        assert_eq!(single_bound_node(MPOL_BIND, &[0; MAX_NODES / MASK_BITS]), None);
+        assert_eq!(single_bound_node(MPOL_DEFAULT, &node_mask(1)), None);
+    }
+
+    #[test]
+    fn node_list_and_cpus_from_sysfs() {
+        let root = std::env::temp_dir().join(format!("rambleed-numa-{}", std::process::id()));
+        for (node, cpulist) in &[(0, "0-3,8-11\n"), (1, "4-7,12-15\n")] {
+            let dir = root.join(format!("node{}", node));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("cpulist"), cpulist).unwrap();
+        }
+        fs::create_dir_all(root.join("node2")).unwrap();
+        // the node lists next to the node directories are not nodes
+        fs::write(root.join("online"), "0-2\n").unwrap();
+        fs::write(root.join("possible"), "0-7\n").unwrap();
+
+        assert_eq!(HugePages::new(&root).nodes(), vec![0, 1, 2]);
+        assert_eq!(node_cpus_below(&root, 0), vec![0, 1, 2, 3, 8, 9, 10, 11]);
+        assert_eq!(node_cpus_below(&root, 1), vec![4, 5, 6, 7, 12, 13, 14, 15]);
+        // memory-only node without cpulist and a missing node
+        assert!(node_cpus_below(&root, 2).is_empty());
+        assert!(node_cpus_below(&root, 3).is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn binding_is_restored_on_drop() {
+        let before = bound_node();
+        // kernels without NUMA support have no memory policies
+        let binding = match NodeBinding::bind(0) {
+            Ok(b) => b,
+            Err(_) => return,
+        };
+        assert_eq!(bound_node(), Some(0));
+        drop(binding);
+        assert_eq!(bound_node(), before);
+        assert!(NodeBinding::bind(MAX_NODES).is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+use std::panic;
+use std::thread;
+
+use crate::architecture::{BankKey, DramAddr};
+use crate::config::Config;
+use crate::memmap::{DramRange, MemMap};
+use crate::profile::{profile_row_shared, Flip};
+use crate::runenv::{allowed_cpus, pin_to_cpu};
+
+type Rows = Vec<(DramAddr, Vec<DramRange>)>;
+
+// all mapped rows grouped by bank
+pub(crate) fn rows_by_bank(mem: &MemMap) -> BTreeMap<BankKey, Rows> {
+    mem.index()
+        .iter()
+        .map(|(b, rows)| {
+            let rows = rows.iter().map(|(&r, rs)| (b.row(r), rs.clone())).collect();
+            (*b, rows)
+        })
+        .collect()
+}
+
+// assign every bank to exactly one of n threads, so no two threads ever touch the same bank
+pub(crate) fn partition_banks(banks: &[BankKey], n: usize) -> Vec<Vec<BankKey>> {
+    let n = n.max(1);
+    let mut parts = vec![Vec::new(); n];
+    for (i, b) in banks.iter().enumerate() {
+        parts[i % n].push(*b);
+    }
+    parts
+}
+
+// safety: no other thread may access the rows of these banks
+unsafe fn profile_banks(mem: &MemMap, rows: Vec<Rows>, c: &Config) -> Vec<Flip> {
+    let mut flips = vec![];
+    for bank in rows {
+        for (da, rs) in bank {
+            flips.append(&mut profile_row_shared(mem, &da, &rs, c));
+        }
+    }
+    flips
+}
+
+// profile all rows of mem with one pinned thread per cpu (at most threads), each owning disjoint banks
+pub(crate) fn template_parallel(mem: &mut MemMap, c: &Config, threads: usize) -> Vec<Flip> {
+    let mut banks = rows_by_bank(mem);
+    let cpus = allowed_cpus();
+    let keys: Vec<BankKey> = banks.keys().cloned().collect();
+    let parts = partition_banks(&keys, threads.min(cpus.len()).min(keys.len()));
+
+    println!("Profiling {} banks on {} threads", keys.len(), parts.len());
+
+    // the exclusive borrow of mem keeps slices of it from being alive while the threads run
+    let mem: &MemMap = mem;
+    thread::scope(|s| {
+        let workers: Vec<_> = parts
+            .into_iter()
+            .enumerate()
+            .map(|(i, part)| {
+                let rows: Vec<Rows> = part.iter().filter_map(|b| banks.remove(b)).collect();
+                let cpu = cpus[i % cpus.len()];
+                s.spawn(move || {
+                    if !pin_to_cpu(cpu) {
+                        println!("Failed to pin thread {} to cpu {}", i, cpu);
+                    }
+                    // every thread owns whole banks, so no two threads touch the same row
+                    unsafe { profile_banks(mem, rows, c) }
+                })
+            })
+            .collect();
+        // the scope joins every thread before returning, even when passing on a panic
+        workers
+            .into_iter()
+            .flat_map(|h| h.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect()
+    })
+}
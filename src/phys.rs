@@ -0,0 +1,222 @@
+use vm_info::page_map::read_page_map;
+use vm_info::page_size;
+use vm_info::ProcessId::SelfPid;
+
+use crate::architecture::PhysAddr;
+
+const ALL_BITS: usize = 64;
+
+// translates virtual to physical addresses, possibly only the low known_bits of them
+pub(crate) trait PhysResolver {
+    fn name(&self) -> &'static str;
+    fn resolve(&self, v: *const u8) -> Option<PhysAddr>;
+    // physical address bits resolve gets right, higher bits are relative to an unknown base
+    fn known_bits(&self) -> usize;
+}
+
+fn mask(bits: usize) -> usize {
+    if bits >= ALL_BITS {
+        usize::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+// /proc/self/pagemap, frame numbers are only visible to root
+pub(crate) struct Pagemap;
+
+impl PhysResolver for Pagemap {
+    fn name(&self) -> &'static str {
+        "pagemap"
+    }
+
+    fn resolve(&self, v: *const u8) -> Option<PhysAddr> {
+        let v = v as usize;
+        let page_size = page_size().unwrap_or(4096);
+        let vpage = read_page_map(SelfPid, v / page_size).ok()?;
+        let frame = vpage.page_frame()?;
+        Some(frame as usize * page_size + v % page_size)
+    }
+
+    fn known_bits(&self) -> usize {
+        ALL_BITS
+    }
+}
+
+// huge pages are aligned virtually and physically, so the offset in the page is known
+pub(crate) struct HugePageRelative {
+    pub bits: usize,
+}
+
+impl PhysResolver for HugePageRelative {
+    fn name(&self) -> &'static str {
+        "huge page relative"
+    }
+
+    fn resolve(&self, v: *const u8) -> Option<PhysAddr> {
+        Some(v as usize & mask(self.bits))
+    }
+
+    fn known_bits(&self) -> usize {
+        self.bits
+    }
+}
+
+// a contiguous buffer whose physical offset to a 1 << bits boundary was measured,
+// e.g. by SPOILER or the row conflict side channel
+pub(crate) struct TimingAlignment {
+    pub base: *const u8,
+    // physical address of base modulo 1 << bits
+    pub offset: usize,
+    pub bits: usize,
+}
+
+impl PhysResolver for TimingAlignment {
+    fn name(&self) -> &'static str {
+        "timing alignment"
+    }
+
+    fn resolve(&self, v: *const u8) -> Option<PhysAddr> {
+        let rel = (v as usize).checked_sub(self.base as usize)?;
+        Some((self.offset + rel) & mask(self.bits))
+    }
+
+    fn known_bits(&self) -> usize {
+        self.bits
+    }
+}
+
+// a contiguous buffer whose offset to any boundary above a page is unknown, e.g. a SPOILER run
+// addresses are relative to the page base lies in, only the page offset bits are physical
+pub(crate) struct RunRelative {
+    pub base: *const u8,
+}
+
+impl PhysResolver for RunRelative {
+    fn name(&self) -> &'static str {
+        "run relative"
+    }
+
+    fn resolve(&self, v: *const u8) -> Option<PhysAddr> {
+        let page_size = page_size().unwrap_or(4096);
+        let rel = (v as usize).checked_sub(self.base as usize)?;
+        Some(self.base as usize % page_size + rel)
+    }
+
+    fn known_bits(&self) -> usize {
+        page_size().unwrap_or(4096).trailing_zeros() as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::alloc::Contiguity;
+    use crate::memmap::MemMap;
+    use crate::testutil::config;
+
+    // fixed virtual to physical page table, e.g. captured from pagemap on another machine
+    struct Fixture {
+        page_size: usize,
+        pages: HashMap<usize, PhysAddr>,
+    }
+
+    impl Fixture {
+        // one "<virtual page address> <physical page address>" pair of hex numbers per line
+        fn parse(s: &str, page_size: usize) -> Result<Self, String> {
+            let mut pages = HashMap::new();
+            for (i, line) in s.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let parts: Vec<&str> = line.split_whitespace().collect();
+                let hex = |x: &str| usize::from_str_radix(x.trim_start_matches("0x"), 16);
+                match (parts.first().map(|x| hex(x)), parts.get(1).map(|x| hex(x))) {
+                    (Some(Ok(v)), Some(Ok(p))) => {
+                        pages.insert(v / page_size, p / page_size * page_size);
+                    }
+                    _ => return Err(format!("line {}: expected two hex addresses", i + 1)),
+                }
+            }
+            Ok(Fixture { page_size, pages })
+        }
+    }
+
+    impl PhysResolver for Fixture {
+        fn name(&self) -> &'static str {
+            "fixture"
+        }
+
+        fn resolve(&self, v: *const u8) -> Option<PhysAddr> {
+            let v = v as usize;
+            Some(self.pages.get(&(v / self.page_size))? + v % self.page_size)
+        }
+
+        fn known_bits(&self) -> usize {
+            ALL_BITS
+        }
+    }
+
+    const PAGE: usize = 4096;
+    const BASE: usize = 0x7f00_0000_0000;
+
+    // two virtual pages backed by frames in the wrong order, a third page missing
+    const PAGEMAP: &str = "# virtual physical
+0x7f0000000000 0x2000
+
+0x7f0000001000 0x1000
+";
+
+
+    #[test]
+    fn fixture_parse_and_resolve() {
+        let f = Fixture::parse(PAGEMAP, PAGE).unwrap();
+        assert_eq!(f.pages.len(), 2);
+        assert_eq!(f.resolve((BASE + 0x10) as *const u8), Some(0x2010));
+        assert_eq!(f.resolve((BASE + PAGE + 0xfff) as *const u8), Some(0x1fff));
+        assert_eq!(f.resolve((BASE + 2 * PAGE) as *const u8), None);
+        assert_eq!(f.known_bits(), ALL_BITS);
+    }
+
+    #[test]
+    fn fixture_parse_errors() {
+        for &(s, line) in &[("0x1000 zz\n", "line 1"), ("\n0x1000\n", "line 2")] {
+            match Fixture::parse(s, PAGE) {
+                Err(e) => assert!(e.starts_with(line), "{}", e),
+                Ok(_) => panic!("parsed {:?}", s),
+            }
+        }
+    }
+
+    #[test]
+    fn memmap_from_fixture() {
+        let c = config(64_000);
+        let f = Fixture::parse(PAGEMAP, PAGE).unwrap();
+        // translating and indexing never touches the buffer
+        let mem = unsafe { MemMap::view(BASE as *mut u8, 3 * PAGE, &f, &c) };
+        assert_eq!(mem.describe_phys(8), "0x2008 (fixture)");
+        assert_eq!(mem.unresolved_pages(), 1);
+        assert_eq!(mem.offset_to_phys(PAGE + 8), Some(0x1008));
+        assert_eq!(mem.phys_to_offset(0x2008), Some(8));
+        assert_eq!(mem.phys_to_offset(0x3000), None);
+        assert_eq!(mem.describe_phys(8), "0x2008 (fixture)");
+
+        // only the two translated pages are in the index
+        let indexed: usize = mem.rows().iter().flat_map(|r| mem.row_ranges(r)).map(|r| r.bytes).sum();
+        assert_eq!(indexed, 2 * PAGE);
+        let da = c.arch.phys_to_dram(0x1008);
+        assert_eq!(mem.dram_to_offset(&da, &c), Some(PAGE + 8));
+    }
+
+    #[test]
+    fn spoiler_runs_only_know_the_page_offset() {
+        let base = (BASE + 0x5000) as *const u8;
+        let r = Contiguity::Aliasing { mbs: 2 }.resolver(base);
+        assert_eq!(r.known_bits(), 12);
+        assert_eq!(r.resolve(base), Some(0));
+        assert_eq!(r.resolve(base.wrapping_add(0x1_0123)), Some(0x1_0123));
+        assert_eq!(r.resolve(base.wrapping_sub(1)), None);
+    }
+}
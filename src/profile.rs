@@ -1,7 +1,8 @@
-use crate::alloc::virt_to_phys_pagemap;
 use crate::architecture::DramAddr;
 use crate::config::Config;
-use crate::hammer::hammer;
+use crate::hammer::{hammer_config, hammer_report, HammerReport};
+use crate::timing::timer;
+use std::collections::HashSet;
 use crate::memmap::{DramRange, MemMap};
 use crate::profile::Direction::{From0To1, From1To0};
 
@@ -11,19 +12,28 @@ pub(crate) enum Direction {
     From0To1,
 }
 
-#[derive(Debug, Clone)]
-pub(crate) struct FlipStats {
-    striped_complement: f64,
-    above_complement: f64,
-    below_complement: f64,
-    uniform: f64,
+// which aggressors caused a flip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Aggression {
+    // rows directly above and below
+    Near,
+    // rows at distance k only
+    Far(u16),
+    // rows at distance k with light hammering of the rows directly above and below
+    HalfDouble(u16),
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct Flip {
     pub(crate) dir: Direction,
     pub pos : DramAddr,
-    pub stats : FlipStats,
+    // activations of each aggressor within one refresh window that caused the flip
+    pub activations : usize,
+    // loop iterations of the hammering that caused the flip
+    pub reads : usize,
+    pub cause : Aggression,
+    // NUMA node of the memory, DRAM addresses of different nodes are on different DIMMs
+    pub node : Option<usize>,
 }
 
 impl Flip {
@@ -31,88 +41,25 @@ impl Flip {
         Flip {
             dir,
             pos,
-            stats : FlipStats {
-                striped_complement: 0.0,
-                above_complement: 0.0,
-                below_complement: 0.0,
-                uniform: 0.0
-            }
+            activations: 0,
+            reads: 0,
+            cause: Aggression::Near,
+            node: None,
         }
     }
 }
 
-fn compl_fill(d : Direction) -> u8 {
-    match d {
-        From0To1 => 0xff,
-        From1To0 => 0x00,
-    }
-}
-
-fn id_fill(d : Direction) -> u8 {
-    match d {
-        From0To1 => 0x00,
-        From1To0 => 0xff,
-    }
-}
-
-fn byte_range(da : &DramAddr) -> Vec<DramRange> {
-    vec![DramRange {
-        start: da.clone(),
-        bytes: 1,
-    }]
-}
-
-fn hammer_bit(mem: &mut MemMap, da : &DramAddr, pat_above : u8, pat_victim : u8, pat_below : u8, c: &Config ) -> bool {
-    let a1 = &da.row_above();
-    let a2 = &da.row_below();
-    let row_above = byte_range(a1);
-    let row_below = byte_range(a2);
-    let row = byte_range(da);
-
-    fill_ranges(mem, &row_above, pat_above, c);
-    fill_ranges(mem, &row, pat_victim, c);
-    fill_ranges(mem, &row_below, pat_below, c);
-
-    hammer(mem.dram_to_virt( &a1, c), mem.dram_to_virt(&a2, c), c.reads_per_hammer);
-
-    let res = *mem.at_dram(da, c) & (1 << da.bit);
-    let before = pat_victim & (1 << da.bit);
-    if res != before {
-        println!("found bit flip");
-    }
-    return *mem.at_dram(da,c) != pat_victim;
-}
-
-pub(crate) fn create_stats(mem: &mut MemMap, flip : &mut Flip, c : &Config) -> () {
-    let n = 20;
-    let to = compl_fill(flip.dir);
-    let from = id_fill(flip.dir);
-
-    let mut striped_flips = 0;
-    let mut uniform_flips = 0;
-    let mut above_flips = 0;
-    let mut below_flips = 0;
-
-    for i in 0..n {
-        println!("{}", i);
-        below_flips += hammer_bit(mem, &flip.pos, from, from, to, c) as usize;
-        striped_flips += hammer_bit(mem, &flip.pos, to, from, to, c) as usize;
-        uniform_flips += hammer_bit(mem, &flip.pos, from, from, from, c) as usize;
-        above_flips += hammer_bit(mem, &flip.pos, to, from, from, c) as usize;
-        }
-
-    flip.stats.above_complement = above_flips as f64 / n as f64;
-    flip.stats.below_complement = below_flips as f64 / n as f64;
-    flip.stats.striped_complement = striped_flips as f64 / n as f64;
-    flip.stats.uniform = uniform_flips as f64 / n as f64;
-}
-
-fn fill_ranges(mem: &mut MemMap, rs: &Vec<DramRange>, p: u8, c: &Config) {
+// writes through the raw mapping, so threads profiling disjoint banks can share mem
+// safety: as for MemMap::write_byte, for all bytes of rs
+unsafe fn fill_ranges(mem: &MemMap, rs: &Vec<DramRange>, p: u8, c: &Config) {
     for r in rs {
-        let start_off = mem.dram_to_offset(&r.start, c);
+        let start_off = match mem.dram_to_offset(&r.start, c) {
+            Some(o) => o,
+            None => continue,
+        };
 
         for i in 0..r.bytes {
-            mem[start_off + i] = p;
+            mem.write_byte(start_off + i, p);
         }
     }
 }
@@ -136,21 +83,27 @@ fn find_flips(da: DramAddr, mut expected: u8, mut actual: u8) -> Vec<Flip> {
     flips
 }
 
-fn flips_in_range(mem: &MemMap, v: &DramRange, expected: u8, c: &Config) -> Vec<Flip> {
+// safety: as for MemMap::read_byte, for all bytes of v
+unsafe fn flips_in_range(mem: &MemMap, v: &DramRange, expected: u8, c: &Config) -> Vec<Flip> {
     let mut flips = Vec::new();
-    let base = mem.dram_to_offset(&v.start, c);
+    let base = match mem.dram_to_offset(&v.start, c) {
+        Some(o) => o,
+        None => return flips,
+    };
 
     for i in 0..v.bytes {
-        let actual = mem[base + i];
+        let actual = match mem.read_byte(base + i) {
+            Some(b) => b,
+            None => break,
+        };
 
         if actual != expected {
-            println!(
-                "Bit flip at physical address: {:p}",
-                virt_to_phys_pagemap(&mem[base + i]).unwrap_or(std::usize::MAX) as *const usize
-            );
+            println!("Bit flip at physical address: {}", mem.describe_phys(base + i));
 
-            let mut cur_flips = find_flips(mem.offset_to_dram(base + i, &c), expected, actual);
-            for f in &cur_flips {
+            let da = mem.offset_to_dram(base + i, c).expect("flipped byte is not mapped");
+            let mut cur_flips = find_flips(da, expected, actual);
+            for f in cur_flips.iter_mut() {
+                f.node = mem.node();
                 println!("Bit: {}, Dir: {:?}", f.pos.bit, f.dir);
             }
             flips.append(&mut cur_flips);
@@ -159,40 +112,28 @@ fn flips_in_range(mem: &MemMap, v: &DramRange, expected: u8, c: &Config) -> Vec<
     flips
 }
 
-pub(crate) fn profile_addr(mem: &mut MemMap, da: &DramAddr, p: u8, c: &Config) -> Vec<Flip> {
-    let da_above = da.row_above();
-    let da_below = da.row_below();
-
-    let row_above = byte_range(&da_above);
-    let row_below = byte_range(&da_below);
-    let row = byte_range(&da);
-
-    fill_ranges(mem, &row_above, p, c);
-    fill_ranges(mem, &row, !p, c);
-    fill_ranges(mem, &row_below, p, c);
-
-    let a1 = mem.dram_to_virt( &da_above, c);
-    let a2 = mem.dram_to_virt(&da_below, c);
-
-    hammer(a1, a2, c.reads_per_hammer);
-
-    let mut flips = Vec::new();
-    flips.append(&mut flips_in_range(
-        mem,
-        &DramRange { start : da.clone(), bytes: 1 },
-        !p,
-        c,
-    ));
-
-    flips
+// fill and hammer the ranges with num_reads reads of r1 and r2, flips in v
+pub(crate) fn profile_ranges_reads(
+    mem: &mut MemMap,
+    r1: &Vec<DramRange>,
+    r2: &Vec<DramRange>,
+    v: &Vec<DramRange>,
+    p: u8,
+    num_reads: usize,
+    c: &Config,
+) -> Vec<Flip> {
+    // mem is borrowed exclusively, so no slice of it is alive
+    unsafe { hammer_ranges(mem, r1, r2, v, p, num_reads, c) }
 }
 
-pub(crate) fn profile_ranges(
-    mem: &mut MemMap,
+// safety: as for MemMap::write_byte, for all bytes of r1, r2 and v
+unsafe fn hammer_ranges(
+    mem: &MemMap,
     r1: &Vec<DramRange>,
     r2: &Vec<DramRange>,
     v: &Vec<DramRange>,
     p: u8,
+    num_reads: usize,
     c: &Config,
 ) -> Vec<Flip> {
     //println!("{:#?}", r1);
@@ -200,19 +141,219 @@ pub(crate) fn profile_ranges(
     //println!("{:#?}", v);
 
     let mut flips = Vec::new();
-    if let (Some (a1), Some(a2)) = (r1.get(0), r2.get(0)){
+    if let (Some (a1), Some(a2)) = (r1.first(), r2.first()){
         fill_ranges(mem, r1, p, c);
         fill_ranges(mem, v, !p, c);
         fill_ranges(mem, r2, p, c);
 
-        let a1 = mem.dram_to_virt(&a1.start, c);
-        let a2 = mem.dram_to_virt( &a2.start, c);
-        hammer(a1, a2, c.reads_per_hammer);
+        let report = match mem.aggressor_pair(&a1.start, &a2.start, c) {
+            Some((a1, a2)) => hammer_report(c, a1, a2, num_reads),
+            None => return flips,
+        };
 
         for v_range in v {
             flips.append(&mut flips_in_range(mem, v_range, !p, c));
         }
+        for f in flips.iter_mut() {
+            f.activations = report.activations_per_refresh;
+            f.reads = num_reads;
+        }
+
+    }
+    flips
+}
+
+// profile all ranges of the row da with both neighbouring rows as aggressors
+pub(crate) fn profile_row(mem: &mut MemMap, da: &DramAddr, rs: &Vec<DramRange>, c: &Config) -> Vec<Flip> {
+    // mem is borrowed exclusively, so no slice of it is alive
+    unsafe { profile_row_shared(mem, da, rs, c) }
+}
+
+// profile_row for threads sharing mem
+// safety: no reference into the row da and its neighbours may be alive, and no other thread
+// may access them, e.g. because every thread owns whole banks
+pub(crate) unsafe fn profile_row_shared(mem: &MemMap, da: &DramAddr, rs: &Vec<DramRange>, c: &Config) -> Vec<Flip> {
+    let mut flips = vec![];
+    if da.row == 0 || da.row == u16::MAX {
+        return flips;
+    }
+
+    let row_above = mem.same_row_ranges(&da.row_above());
+    let row_below = mem.same_row_ranges(&da.row_below());
+
+    flips.append(&mut hammer_ranges(mem, &row_above, &row_below, rs, 0x00, c.reads_per_hammer, c));
+    flips.append(&mut hammer_ranges(mem, &row_above, &row_below, rs, 0xff, c.reads_per_hammer, c));
+    flips
+}
+
+// reads of the far aggressors between two rounds of near aggressor hammering
+const FAR_CHUNK: usize = 1000;
+
+fn fill_neighbourhood(mem: &mut MemMap, da: &DramAddr, rs: &Vec<DramRange>, k: u16, p: u8, c: &Config) {
+    for d in 1..=k as i32 {
+        for &sign in &[-1, 1] {
+            if let Some(n) = da.row_at(sign * d) {
+                let ranges = mem.same_row_ranges(&n);
+                // mem is borrowed exclusively, so no slice of it is alive
+                unsafe { fill_ranges(mem, &ranges, p, c) };
+            }
+        }
+    }
+    unsafe { fill_ranges(mem, rs, !p, c) };
+}
+
+// hammer the far pair, interleaved with near_ratio as many reads of the near pair
+// the report counts the reads of the far pair
+fn hammer_far_near(
+    mem: &MemMap,
+    far: &(DramAddr, DramAddr),
+    near: Option<&(DramAddr, DramAddr)>,
+    near_ratio: f64,
+    c: &Config,
+) -> HammerReport {
+    let far = match mem.aggressor_pair(&far.0, &far.1, c) {
+        Some(p) => p,
+        None => return HammerReport::default(),
+    };
+    let near = near.and_then(|n| mem.aggressor_pair(&n.0, &n.1, c));
+    let near_chunk = (FAR_CHUNK as f64 * near_ratio) as usize;
+    let t = timer();
+    let t0 = t.now();
+    let mut done = 0;
+    while done < c.reads_per_hammer {
+        let n = std::cmp::min(FAR_CHUNK, c.reads_per_hammer - done);
+        hammer_config(c, far.0, far.1, n);
+        if let Some(near) = near {
+            hammer_config(c, near.0, near.1, near_chunk);
+        }
+        done += n;
+    }
+    HammerReport::new(c, done, t.elapsed_us(t0))
+}
+
+fn first_addr(mem: &MemMap, da: &DramAddr) -> Option<DramAddr> {
+    Some(mem.row_ranges(da).first()?.start.clone())
+}
+
+fn collect_flips(
+    mem: &mut MemMap,
+    rs: &Vec<DramRange>,
+    p: u8,
+    cause: Aggression,
+    report: &HammerReport,
+    c: &Config,
+) -> Vec<Flip> {
+    let mut flips = vec![];
+    for r in rs {
+        // mem is borrowed exclusively, so no slice of it is alive
+        flips.append(&mut unsafe { flips_in_range(mem, r, !p, c) });
+    }
+    for f in flips.iter_mut() {
+        f.activations = report.activations_per_refresh;
+        f.reads = report.requested_reads;
+        f.cause = cause;
+    }
+    flips
+}
+
+// flips of the combined hammering that neither the far nor the near aggressors cause alone
+fn only_combined(combined: Vec<Flip>, far: &[Flip], near: &[Flip]) -> Vec<Flip> {
+    let known: HashSet<&DramAddr> = far.iter().chain(near.iter()).map(|f| &f.pos).collect();
+    combined.into_iter().filter(|f| !known.contains(&f.pos)).collect()
+}
 
+// profile the row da with aggressors at distance k (Half-Double style for k = 2)
+// flips are attributed to the far aggressors alone, the lightly hammered near aggressors alone,
+// or only the combination of both
+pub(crate) fn profile_row_distance(
+    mem: &mut MemMap,
+    da: &DramAddr,
+    rs: &Vec<DramRange>,
+    k: u16,
+    near_ratio: f64,
+    p: u8,
+    c: &Config,
+) -> Vec<Flip> {
+    let far = match (da.row_at(-(k as i32)), da.row_at(k as i32)) {
+        (Some(a), Some(b)) => match (first_addr(mem, &a), first_addr(mem, &b)) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return vec![],
+        },
+        _ => return vec![],
+    };
+    let near = match (da.row_at(-1), da.row_at(1)) {
+        (Some(a), Some(b)) => match (first_addr(mem, &a), first_addr(mem, &b)) {
+            (Some(a), Some(b)) if near_ratio > 0.0 && k > 1 => Some((a, b)),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    fill_neighbourhood(mem, da, rs, k, p, c);
+    let report = hammer_far_near(mem, &far, None, 0.0, c);
+    let mut flips = collect_flips(mem, rs, p, Aggression::Far(k), &report, c);
+
+    if let Some(near) = &near {
+        // near aggressors alone, with the same light amount
+        fill_neighbourhood(mem, da, rs, k, p, c);
+        let near_reads = (c.reads_per_hammer as f64 * near_ratio) as usize;
+        let report = match mem.aggressor_pair(&near.0, &near.1, c) {
+            Some((a1, a2)) => hammer_report(c, a1, a2, near_reads),
+            None => HammerReport::default(),
+        };
+        let mut near_flips = collect_flips(mem, rs, p, Aggression::Near, &report, c);
+
+        fill_neighbourhood(mem, da, rs, k, p, c);
+        let report = hammer_far_near(mem, &far, Some(near), near_ratio, c);
+        let combined = collect_flips(mem, rs, p, Aggression::HalfDouble(k), &report, c);
+        let mut combined = only_combined(combined, &flips, &near_flips);
+
+        flips.append(&mut near_flips);
+        flips.append(&mut combined);
     }
     flips
 }
+
+// profile every row of mem with aggressors at distance k
+pub(crate) fn profile_distance(mem: &mut MemMap, k: u16, near_ratio: f64, c: &Config) -> Vec<Flip> {
+    let mut flips = vec![];
+    for da in mem.rows() {
+        let rs = mem.same_row_ranges(&da);
+        flips.append(&mut profile_row_distance(mem, &da, &rs, k, near_ratio, 0x00, c));
+        flips.append(&mut profile_row_distance(mem, &da, &rs, k, near_ratio, 0xff, c));
+    }
+    flips
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flip(row: u16, bit: u8, cause: Aggression) -> Flip {
+        let mut f = Flip::new(From0To1, DramAddr { chan: 0, dimm: 0, rank: 0, bank: 0, row, col: 0, byte: 0, bit });
+        f.cause = cause;
+        f
+    }
+
+    #[test]
+    fn combined_flips_exclude_far_and_near_flips() {
+        let far = vec![flip(5, 0, Aggression::Far(2))];
+        let near = vec![flip(5, 1, Aggression::Near)];
+        let combined = vec![
+            flip(5, 0, Aggression::HalfDouble(2)),
+            flip(5, 1, Aggression::HalfDouble(2)),
+            flip(5, 2, Aggression::HalfDouble(2)),
+        ];
+        let only = only_combined(combined, &far, &near);
+        assert_eq!(only.len(), 1);
+        assert_eq!(only[0].pos.bit, 2);
+        assert_eq!(only[0].cause, Aggression::HalfDouble(2));
+    }
+
+    #[test]
+    fn combined_flips_without_known_flips() {
+        let combined = vec![flip(5, 3, Aggression::HalfDouble(2)), flip(6, 3, Aggression::HalfDouble(2))];
+        assert_eq!(only_combined(combined, &[], &[]).len(), 2);
+        assert!(only_combined(vec![], &[flip(5, 3, Aggression::Far(2))], &[]).is_empty());
+    }
+}
@@ -0,0 +1,319 @@
+use crate::hammer::{hammer_with, HammerKernel};
+use crate::timing::timer;
+
+// refresh commands per refresh window (DDR3/DDR4)
+const REFRESH_COMMANDS: u64 = 8192;
+// latencies this many scaled median absolute deviations above the median are spikes
+const SPIKE_MAD: f64 = 6.0;
+// spike gaps shorter than (1 - NOISE_FRACTION) of the typical gap are caused by noise
+const NOISE_FRACTION: f64 = 0.25;
+const TRACE_SAMPLES: usize = 200_000;
+// refresh periods outside of this range are measurement errors
+const MIN_REFRESH_US: usize = 8_000;
+const MAX_REFRESH_US: usize = 256_000;
+// synchronized hammering leaves this fraction of each tREFI idle to absorb jitter
+const PACE_SLACK: f64 = 0.1;
+const PACE_PROBE_READS: usize = 10_000;
+// waiting for a refresh gives up after this many predicted refresh periods without one
+const MAX_WAIT_PERIODS: f64 = 64.0;
+
+// a single timed access to a row conflict pair: (start timestamp, latency) in TSC ticks
+pub(crate) type Sample = (u64, u64);
+
+#[derive(Clone, Debug)]
+pub(crate) struct RefreshTiming {
+    pub trefi_ns: f64,
+    pub refresh_period_us: usize,
+    pub spikes: usize,
+}
+
+pub(crate) fn time_access(a1: *const u8, a2: *const u8) -> Sample {
+    let t = timer();
+    let start = t.now();
+    unsafe {
+        std::ptr::read_volatile(a1);
+        std::ptr::read_volatile(a2);
+        asm!("clflush [$0]\n\t\
+              clflush [$1]"
+              :
+              : "r"(a1), "r"(a2)
+              :
+              : "volatile", "memory", "intel");
+    }
+    (start, t.elapsed(start))
+}
+
+// record timed accesses to a row conflict pair
+pub(crate) fn latency_trace(a1: *const u8, a2: *const u8, n: usize) -> Vec<Sample> {
+    let mut trace = Vec::with_capacity(n);
+    for _ in 0..n {
+        trace.push(time_access(a1, a2));
+    }
+    trace
+}
+
+fn median(sorted: &[u64]) -> f64 {
+    let n = sorted.len();
+    if n % 2 == 1 {
+        sorted[n / 2] as f64
+    } else {
+        (sorted[n / 2 - 1] + sorted[n / 2]) as f64 / 2.0
+    }
+}
+
+// latency above which an access is considered delayed by a refresh
+pub(crate) fn spike_threshold(trace: &[Sample]) -> Option<u64> {
+    if trace.is_empty() {
+        return None;
+    }
+    let mut lat: Vec<u64> = trace.iter().map(|s| s.1).collect();
+    lat.sort();
+    let med = median(&lat);
+    let mut devs: Vec<u64> = lat.iter().map(|&l| (l as f64 - med).abs() as u64).collect();
+    devs.sort();
+    let mad = (1.4826 * median(&devs)).max(1.0);
+    Some((med + SPIKE_MAD * mad) as u64)
+}
+
+// timestamps of the latency spikes in the trace, consecutive spiking samples are merged
+pub(crate) fn spike_times(trace: &[Sample], threshold: u64) -> Vec<u64> {
+    let mut spikes = Vec::new();
+    let mut in_spike = false;
+    for &(t, l) in trace {
+        if l >= threshold {
+            if !in_spike {
+                spikes.push(t);
+            }
+            in_spike = true;
+        } else {
+            in_spike = false;
+        }
+    }
+    spikes
+}
+
+// estimate the spacing of refresh commands from spike timestamps
+// spacings are folded onto the smallest common period, so missed spikes do not matter
+pub(crate) fn estimate_trefi(spikes: &[u64]) -> Option<f64> {
+    let mut gaps: Vec<u64> = spikes.windows(2).map(|w| w[1] - w[0]).collect();
+    if gaps.len() < 2 {
+        return None;
+    }
+    gaps.sort();
+    let first = median(&gaps[..gaps.len().div_ceil(4)]);
+
+    // drop gaps from noise that are much shorter than the refresh spacing
+    let gaps: Vec<u64> = gaps
+        .into_iter()
+        .filter(|&g| g as f64 >= (1.0 - NOISE_FRACTION) * first)
+        .collect();
+    if gaps.is_empty() {
+        return None;
+    }
+
+    let mut sum = 0.0;
+    let mut count = 0.0;
+    for &g in &gaps {
+        let k = (g as f64 / first).round().max(1.0);
+        sum += g as f64;
+        count += k;
+    }
+    Some(sum / count)
+}
+
+pub(crate) fn detect_refresh(trace: &[Sample], tsc_per_us: f64) -> Option<RefreshTiming> {
+    let threshold = spike_threshold(trace)?;
+    let spikes = spike_times(trace, threshold);
+    let trefi_ticks = estimate_trefi(&spikes)?;
+    let trefi_ns = trefi_ticks * 1000.0 / tsc_per_us;
+    let refresh_period_us = (trefi_ns * REFRESH_COMMANDS as f64 / 1000.0).round() as usize;
+    if !(MIN_REFRESH_US..=MAX_REFRESH_US).contains(&refresh_period_us) {
+        return None;
+    }
+
+    Some(RefreshTiming {
+        trefi_ns,
+        refresh_period_us,
+        spikes: spikes.len(),
+    })
+}
+
+pub(crate) fn measure_refresh(a1: *const u8, a2: *const u8) -> Option<RefreshTiming> {
+    let trace = latency_trace(a1, a2, TRACE_SAMPLES);
+    detect_refresh(&trace, timer().ticks_per_us)
+}
+
+// timing of refresh commands in TSC ticks, derived from a latency trace
+#[derive(Clone, Debug)]
+pub(crate) struct RefCadence {
+    pub period_ticks: f64,
+    // timestamp of a refresh command
+    pub phase_ticks: u64,
+    // latency above which an access is delayed by a refresh
+    pub threshold: u64,
+}
+
+impl RefCadence {
+    // first predicted refresh command at or after now
+    pub(crate) fn next_ref(&self, now: u64) -> u64 {
+        if now <= self.phase_ticks {
+            return self.phase_ticks;
+        }
+        let k = ((now - self.phase_ticks) as f64 / self.period_ticks).ceil();
+        self.phase_ticks + (k * self.period_ticks) as u64
+    }
+}
+
+pub(crate) fn detect_cadence(trace: &[Sample]) -> Option<RefCadence> {
+    let threshold = spike_threshold(trace)?;
+    let spikes = spike_times(trace, threshold);
+    let period = estimate_trefi(&spikes)?;
+
+    // fold all spikes onto the first one and take the median offset
+    let first = spikes[0];
+    let mut offsets: Vec<i64> = spikes
+        .iter()
+        .map(|&t| {
+            let r = ((t - first) as f64 % period) as i64;
+            if r as f64 > period / 2.0 {
+                r - period as i64
+            } else {
+                r
+            }
+        })
+        .collect();
+    offsets.sort();
+
+    Some(RefCadence {
+        period_ticks: period,
+        phase_ticks: (first as i64 + offsets[offsets.len() / 2]) as u64,
+        threshold,
+    })
+}
+
+// split num_reads into chunks of at most reads_per_ref, chunk i starts at the i-th refresh after start
+pub(crate) fn sync_schedule(
+    cadence: &RefCadence,
+    start: u64,
+    reads_per_ref: usize,
+    num_reads: usize,
+) -> Vec<(u64, usize)> {
+    let mut schedule = Vec::new();
+    let first = cadence.next_ref(start);
+    let mut done = 0;
+    let mut i = 0;
+    while done < num_reads && reads_per_ref > 0 {
+        let n = std::cmp::min(reads_per_ref, num_reads - done);
+        schedule.push((first + (i as f64 * cadence.period_ticks) as u64, n));
+        done += n;
+        i += 1;
+    }
+    schedule
+}
+
+// busy wait until an access to the row conflict pair is delayed by a refresh
+// None if none shows up in time, e.g. because the threshold is too high
+pub(crate) fn wait_for_ref(a1: *const u8, a2: *const u8, cadence: &RefCadence) -> Option<u64> {
+    let deadline = timer().now() + (cadence.period_ticks * MAX_WAIT_PERIODS) as u64;
+    loop {
+        let (t, l) = time_access(a1, a2);
+        if l >= cadence.threshold {
+            return Some(t);
+        }
+        if t > deadline {
+            return None;
+        }
+    }
+}
+
+// the aggressors double as the probe pair, since they are a row conflict pair
+pub(crate) fn hammer_synchronized(
+    kernel: HammerKernel,
+    a1: *const u8,
+    a2: *const u8,
+    num_reads: usize,
+    cadence: &RefCadence,
+) {
+    // how many reads fit between two refresh commands
+    let t = timer();
+    let t0 = t.now();
+    hammer_with(kernel, a1, a2, PACE_PROBE_READS);
+    let ticks_per_read = t.elapsed(t0) as f64 / PACE_PROBE_READS as f64;
+    let reads_per_ref = (cadence.period_ticks * (1.0 - PACE_SLACK) / ticks_per_read) as usize;
+
+    let mut synced = cadence.clone();
+    synced.phase_ticks = match wait_for_ref(a1, a2, cadence) {
+        Some(t) => t,
+        None => {
+            println!("No refresh seen, hammering unsynchronized");
+            hammer_with(kernel, a1, a2, num_reads);
+            return;
+        }
+    };
+
+    for (start, n) in sync_schedule(&synced, synced.phase_ticks, reads_per_ref.max(1), num_reads) {
+        while t.now() < start {}
+        hammer_with(kernel, a1, a2, n);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TREFI: u64 = 7800;
+    const PHASE: u64 = 3000;
+
+    // one sample every 100 ticks, delayed whenever a refresh command is issued
+    fn synthetic_trace(n: u64, skip_every: u64) -> Vec<Sample> {
+        (0..n)
+            .map(|i| {
+                let t = i * 100;
+                let k = t / TREFI;
+                let refresh = t % TREFI == PHASE && (skip_every == 0 || !k.is_multiple_of(skip_every));
+                (t, if refresh { 400 } else { 200 + i % 5 })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cadence_from_synthetic_trace() {
+        let cadence = detect_cadence(&synthetic_trace(20_000, 0)).unwrap();
+        assert!((cadence.period_ticks - TREFI as f64).abs() < 1.0);
+        assert_eq!(cadence.phase_ticks, PHASE);
+        assert!(cadence.threshold > 204 && cadence.threshold <= 400);
+        assert_eq!(cadence.next_ref(PHASE + 1), PHASE + TREFI);
+        assert_eq!(cadence.next_ref(0), PHASE);
+    }
+
+    #[test]
+    fn cadence_with_missed_refreshes() {
+        let cadence = detect_cadence(&synthetic_trace(20_000, 3)).unwrap();
+        assert!((cadence.period_ticks - TREFI as f64).abs() < 1.0);
+        assert_eq!(cadence.phase_ticks % TREFI, PHASE);
+    }
+
+    #[test]
+    fn no_cadence_without_spikes() {
+        let flat: Vec<Sample> = (0..1000).map(|i| (i * 100, 200)).collect();
+        assert!(detect_cadence(&flat).is_none());
+    }
+
+    #[test]
+    fn refresh_period_from_synthetic_trace() {
+        // 1000 ticks per us: tREFI of 7.8 us, 8192 refresh commands per window
+        let t = detect_refresh(&synthetic_trace(20_000, 0), 1000.0).unwrap();
+        assert!((t.trefi_ns - 7800.0).abs() < 1.0);
+        assert_eq!(t.refresh_period_us, 63898);
+    }
+
+    #[test]
+    fn schedule_starts_at_next_refresh() {
+        let cadence = RefCadence { period_ticks: TREFI as f64, phase_ticks: PHASE, threshold: 300 };
+        assert_eq!(
+            sync_schedule(&cadence, PHASE + 1, 100, 250),
+            vec![(PHASE + TREFI, 100), (PHASE + 2 * TREFI, 100), (PHASE + 3 * TREFI, 50)]
+        );
+        assert!(sync_schedule(&cadence, 0, 0, 250).is_empty());
+    }
+}
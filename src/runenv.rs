@@ -0,0 +1,271 @@
+use std::fs;
+use std::io;
+use std::mem;
+use std::thread;
+use std::time::Duration;
+
+use nix::libc;
+
+use crate::timing::timer;
+
+const CPU_ROOT: &str = "/sys/devices/system/cpu";
+const NOISE_WINDOW_MS: u64 = 200;
+// a gap between two timestamps longer than this is an interruption
+const NOISE_GAP_NS: f64 = 5_000.0;
+// SMT sibling utilization above which measurements get skewed
+const MAX_SIBLING_BUSY: f64 = 0.05;
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RunOptions {
+    pub cpu: Option<usize>,
+    pub sched_fifo: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RunMetadata {
+    pub cpu: Option<usize>,
+    // NUMA node of the profiled memory, set once it is allocated
+    pub node: Option<usize>,
+    pub pinned: bool,
+    pub sched_fifo: bool,
+    pub governor: Option<String>,
+    pub smt_siblings: Vec<usize>,
+    pub sibling_busy: Option<f64>,
+    pub irqs_per_sec: Option<f64>,
+    pub interruptions_per_sec: f64,
+    pub warnings: Vec<String>,
+}
+
+impl RunMetadata {
+    pub(crate) fn serialize(&self) -> String {
+        let t = timer();
+        let mut s = String::new();
+        s += &format!("cpu={:?}\n", self.cpu);
+        s += &format!("node={:?}\n", self.node);
+        s += &format!("pinned={}\n", self.pinned);
+        s += &format!("sched_fifo={}\n", self.sched_fifo);
+        s += &format!("governor={}\n", self.governor.as_ref().map_or("unknown", |g| g.as_str()));
+        s += &format!("smt_siblings={:?}\n", self.smt_siblings);
+        s += &format!("sibling_busy={:?}\n", self.sibling_busy);
+        s += &format!("irqs_per_sec={:?}\n", self.irqs_per_sec);
+        s += &format!("interruptions_per_sec={:.1}\n", self.interruptions_per_sec);
+        s += &format!("timer={:?}\n", t.method);
+        s += &format!("invariant_tsc={}\n", t.invariant_tsc);
+        s += &format!("ticks_per_us={:.3}\n", t.ticks_per_us);
+        for w in &self.warnings {
+            s += &format!("warning={}\n", w);
+        }
+        s
+    }
+
+    pub(crate) fn write(&self, path: &str) -> io::Result<()> {
+        fs::write(path, self.serialize())
+    }
+
+    fn warn(&mut self, w: String) {
+        println!("Warning: {}", w);
+        self.warnings.push(w);
+    }
+}
+
+// "0-2,4" -> [0, 1, 2, 4]
+pub(crate) fn parse_cpu_list(s: &str) -> Vec<usize> {
+    let mut cpus = vec![];
+    for part in s.trim().split(',').filter(|p| !p.is_empty()) {
+        let mut it = part.splitn(2, '-');
+        let lo = it.next().and_then(|x| x.trim().parse().ok());
+        let hi = it.next().and_then(|x| x.trim().parse().ok());
+        match (lo, hi) {
+            (Some(lo), Some(hi)) => cpus.extend(lo..=hi),
+            (Some(lo), None) => cpus.push(lo),
+            _ => {}
+        }
+    }
+    cpus
+}
+
+// cpus this process may run on
+pub(crate) fn allowed_cpus() -> Vec<usize> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        if libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return vec![0];
+        }
+        (0..libc::CPU_SETSIZE as usize)
+            .filter(|&i| libc::CPU_ISSET(i, &set))
+            .collect()
+    }
+}
+
+// pin the calling thread to a single cpu
+pub(crate) fn pin_to_cpu(cpu: usize) -> bool {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) == 0
+    }
+}
+
+// needs CAP_SYS_NICE
+pub(crate) fn set_sched_fifo() -> bool {
+    unsafe {
+        let param = libc::sched_param {
+            sched_priority: libc::sched_get_priority_max(libc::SCHED_FIFO),
+        };
+        libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) == 0
+    }
+}
+
+pub(crate) fn governor(cpu: usize) -> Option<String> {
+    fs::read_to_string(format!("{}/cpu{}/cpufreq/scaling_governor", CPU_ROOT, cpu))
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+pub(crate) fn smt_siblings(cpu: usize) -> Vec<usize> {
+    fs::read_to_string(format!("{}/cpu{}/topology/thread_siblings_list", CPU_ROOT, cpu))
+        .map(|s| parse_cpu_list(&s))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|&c| c != cpu)
+        .collect()
+}
+
+// (busy, total) jiffies of a cpu from /proc/stat
+pub(crate) fn parse_cpu_stat(stat: &str, cpu: usize) -> Option<(u64, u64)> {
+    let name = format!("cpu{}", cpu);
+    let line = stat.lines().find(|l| l.split_whitespace().next() == Some(name.as_str()))?;
+    let vals: Vec<u64> = line.split_whitespace().skip(1).filter_map(|v| v.parse().ok()).collect();
+    // idle and iowait are the 4th and 5th columns
+    let idle = vals.get(3)? + vals.get(4).unwrap_or(&0);
+    let total: u64 = vals.iter().sum();
+    Some((total - idle, total))
+}
+
+// interrupts delivered to a cpu, summed over all lines of /proc/interrupts
+pub(crate) fn parse_interrupts(interrupts: &str, cpu: usize) -> Option<u64> {
+    let mut lines = interrupts.lines();
+    let header: Vec<&str> = lines.next()?.split_whitespace().collect();
+    let col = header.iter().position(|&h| h == format!("CPU{}", cpu))?;
+    Some(
+        lines
+            .filter_map(|l| l.split_whitespace().nth(col + 1)?.parse::<u64>().ok())
+            .sum(),
+    )
+}
+
+fn sibling_busy(siblings: &[usize]) -> Option<f64> {
+    let before = fs::read_to_string("/proc/stat").ok()?;
+    thread::sleep(Duration::from_millis(NOISE_WINDOW_MS));
+    let after = fs::read_to_string("/proc/stat").ok()?;
+
+    let mut busy = 0;
+    let mut total = 0;
+    for &s in siblings {
+        let (b0, t0) = parse_cpu_stat(&before, s)?;
+        let (b1, t1) = parse_cpu_stat(&after, s)?;
+        busy += b1 - b0;
+        total += t1 - t0;
+    }
+    if total == 0 {
+        return Some(0.0);
+    }
+    Some(busy as f64 / total as f64)
+}
+
+// spin on the timer and count gaps, which are interrupts or preemptions on this cpu
+fn measure_noise(cpu: Option<usize>) -> (Option<f64>, f64) {
+    let before = cpu.and_then(|c| parse_interrupts(&fs::read_to_string("/proc/interrupts").ok()?, c));
+
+    let t = timer();
+    let gap = t.ns_to_ticks(NOISE_GAP_NS);
+    let window = t.ns_to_ticks(NOISE_WINDOW_MS as f64 * 1e6);
+    let start = t.now();
+    let mut last = start;
+    let mut gaps = 0;
+    while last - start < window {
+        let now = t.now();
+        if now - last > gap {
+            gaps += 1;
+        }
+        last = now;
+    }
+    let secs = NOISE_WINDOW_MS as f64 / 1000.0;
+
+    let after = cpu.and_then(|c| parse_interrupts(&fs::read_to_string("/proc/interrupts").ok()?, c));
+    let irqs = match (before, after) {
+        (Some(b), Some(a)) => Some((a - b) as f64 / secs),
+        _ => None,
+    };
+    (irqs, gaps as f64 / secs)
+}
+
+// pin, set the scheduling policy and record everything that may skew measurements
+pub(crate) fn prepare_run(opts: &RunOptions) -> RunMetadata {
+    let mut meta = RunMetadata {
+        cpu: opts.cpu,
+        ..Default::default()
+    };
+
+    if let Some(cpu) = opts.cpu {
+        meta.pinned = pin_to_cpu(cpu);
+        if !meta.pinned {
+            meta.warn(format!("failed to pin to cpu {}", cpu));
+        }
+    }
+    if opts.sched_fifo {
+        meta.sched_fifo = set_sched_fifo();
+        if !meta.sched_fifo {
+            meta.warn(String::from("failed to set SCHED_FIFO, needs CAP_SYS_NICE"));
+        }
+    }
+
+    // without pinning the run may move between cpus, so no single governor or sibling set applies
+    if let Some(cpu) = opts.cpu {
+        meta.governor = governor(cpu);
+        if let Some(g) = meta.governor.clone().filter(|g| g != "performance") {
+            meta.warn(format!("cpu {} uses the {} governor, timings will vary with frequency", cpu, g));
+        }
+
+        meta.smt_siblings = smt_siblings(cpu);
+        if !meta.smt_siblings.is_empty() {
+            meta.sibling_busy = sibling_busy(&meta.smt_siblings);
+            if let Some(busy) = meta.sibling_busy.filter(|&b| b > MAX_SIBLING_BUSY) {
+                meta.warn(format!(
+                    "SMT siblings {:?} are {:.0}% busy",
+                    meta.smt_siblings,
+                    busy * 100.0
+                ));
+            }
+        }
+    }
+
+    let (irqs, interruptions) = measure_noise(opts.cpu);
+    meta.irqs_per_sec = irqs;
+    meta.interruptions_per_sec = interruptions;
+
+    meta
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_list() {
+        assert_eq!(parse_cpu_list("0-2,4\n"), vec![0, 1, 2, 4]);
+        assert_eq!(parse_cpu_list("3"), vec![3]);
+        assert!(parse_cpu_list("\n").is_empty());
+    }
+
+    #[test]
+    fn unpinned_run_has_no_cpu_environment() {
+        let meta = prepare_run(&RunOptions::default());
+        assert_eq!(meta.cpu, None);
+        assert!(!meta.pinned);
+        assert_eq!(meta.governor, None);
+        assert!(meta.smt_siblings.is_empty());
+        assert_eq!(meta.sibling_busy, None);
+        assert_eq!(meta.irqs_per_sec, None);
+    }
+}
@@ -0,0 +1,226 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::ptr::{read_volatile, write_volatile};
+
+use crate::alloc::{map_eager, unmap, AllocError, Allocation, AllocationStrategy, Contiguity};
+use crate::config::Config;
+use crate::memmap::MemMap;
+use crate::timing::timer;
+
+const PAGE_SIZE: usize = 4096;
+const PAGES_PER_MB: usize = 256;
+// stores in flight before the timed load
+const WINDOW: usize = 64;
+const ROUNDS: usize = 100;
+// a page is a peak if its latency is this many MADs above the median
+const PEAK_MADS: f64 = 8.0;
+// peaks closer than this are one aliasing event smeared over neighbouring pages
+const PEAK_CLUSTER: usize = 4;
+
+// contiguous run found by SPOILER, start_page aliases the probe in the low 20 physical bits
+// all runs of one trace therefore share the same offset to a 1 MB boundary
+// mem is a view into the measured buffer, which has to stay mapped while the run is used
+pub(crate) struct SpoilerRun {
+    pub start_page: usize,
+    pub mbs: usize,
+    pub mem: MemMap,
+}
+
+// the store into page p of buf at the page offset of probe, only such stores can alias it
+fn store_addr(buf: *mut u8, p: usize, probe: *const u8) -> *mut u8 {
+    buf.wrapping_add(p * PAGE_SIZE + probe as usize % PAGE_SIZE)
+}
+
+// latency of a load from probe after WINDOW stores ending at page p, for every page of buf
+// loads are slowed down when a pending store aliases the probe in the low 20 physical bits
+// probe need not be page aligned, the stores go to its page offset
+pub(crate) fn measure_spoiler(buf: *mut u8, len: usize, probe: *const u8) -> Vec<u64> {
+    let pages = len / PAGE_SIZE;
+    let t = timer();
+    let mut trace = vec![0; pages];
+    let mut samples = Vec::with_capacity(ROUNDS);
+
+    for (p, latency) in trace.iter_mut().enumerate().skip(WINDOW) {
+        samples.clear();
+        for _ in 0..ROUNDS {
+            unsafe {
+                for i in (0..=WINDOW).rev() {
+                    write_volatile(store_addr(buf, p - i, probe), 0);
+                }
+                let t0 = t.now();
+                read_volatile(probe);
+                samples.push(t.now() - t0);
+            }
+        }
+        samples.sort();
+        *latency = samples[samples.len() / 2];
+    }
+    trace
+}
+
+fn median(sorted: &[u64]) -> f64 {
+    let n = sorted.len();
+    if n % 2 == 1 {
+        sorted[n / 2] as f64
+    } else {
+        (sorted[n / 2 - 1] + sorted[n / 2]) as f64 / 2.0
+    }
+}
+
+// pages with a latency far above the rest of the trace, one page per cluster
+// unmeasured pages (latency 0) are ignored
+pub(crate) fn find_peaks(trace: &[u64]) -> Vec<usize> {
+    let mut sorted: Vec<u64> = trace.iter().cloned().filter(|&l| l > 0).collect();
+    if sorted.len() < 3 {
+        return vec![];
+    }
+    sorted.sort();
+    let med = median(&sorted);
+    let mut devs: Vec<u64> = sorted.iter().map(|&l| (l as f64 - med).abs() as u64).collect();
+    devs.sort();
+    // a perfectly flat trace still needs a difference of one tick
+    let threshold = med + PEAK_MADS * median(&devs).max(1.0);
+
+    let mut peaks: Vec<usize> = vec![];
+    for (p, &l) in trace.iter().enumerate() {
+        if (l as f64) < threshold {
+            continue;
+        }
+        match peaks.last_mut() {
+            Some(last) if p - *last < PEAK_CLUSTER => {
+                if l > trace[*last] {
+                    *last = p;
+                }
+            }
+            _ => peaks.push(p),
+        }
+    }
+    peaks
+}
+
+// (start page, length in MB) of every chain of peaks exactly 1 MB apart spanning at least min_mb
+pub(crate) fn contiguous_runs(peaks: &[usize], min_mb: usize) -> Vec<(usize, usize)> {
+    let mut runs = vec![];
+    let mut i = 0;
+    while i < peaks.len() {
+        let mut j = i;
+        while j + 1 < peaks.len() && peaks[j + 1] - peaks[j] == PAGES_PER_MB {
+            j += 1;
+        }
+        let mbs = j - i;
+        if mbs >= min_mb.max(1) {
+            runs.push((peaks[i], mbs));
+        }
+        i = j + 1;
+    }
+    runs
+}
+
+pub(crate) fn export_trace_csv(trace: &[u64], path: &str) -> io::Result<()> {
+    let mut f = File::create(path)?;
+    writeln!(f, "page,latency")?;
+    for (p, l) in trace.iter().enumerate() {
+        writeln!(f, "{},{}", p, l)?;
+    }
+    Ok(())
+}
+
+// physically contiguous runs of at least min_mb inside buf, found without pagemap
+pub(crate) fn spoiler_runs(buf: *mut u8, len: usize, min_mb: usize, c: &Config) -> Vec<SpoilerRun> {
+    let probe = vec![0u8; PAGE_SIZE];
+    let trace = measure_spoiler(buf, len, probe.as_ptr());
+    runs_from_trace(buf, &trace, min_mb, c)
+}
+
+pub(crate) fn runs_from_trace(buf: *mut u8, trace: &[u64], min_mb: usize, c: &Config) -> Vec<SpoilerRun> {
+    contiguous_runs(&find_peaks(trace), min_mb)
+        .into_iter()
+        .map(|(start_page, mbs)| {
+            let start = buf.wrapping_add(start_page * PAGE_SIZE);
+            let contiguity = Contiguity::Aliasing { mbs };
+            SpoilerRun {
+                start_page,
+                mbs,
+                mem: unsafe { MemMap::view(start, mbs << 20, &*contiguity.resolver(start), c) },
+            }
+        })
+        .collect()
+}
+
+// map a large buffer and keep the longest SPOILER run, works without root
+pub(crate) struct Spoiler {
+    pub buffer_mb: usize,
+    pub min_mb: usize,
+}
+
+impl Default for Spoiler {
+    fn default() -> Self {
+        Spoiler { buffer_mb: 256, min_mb: 2 }
+    }
+}
+
+impl AllocationStrategy for Spoiler {
+    fn name(&self) -> &'static str {
+        "SPOILER"
+    }
+
+    fn allocate(&self, c: &Config) -> Result<Allocation, AllocError> {
+        let (buf, len) = map_eager(self.buffer_mb << 20)?;
+        let best = spoiler_runs(buf, len, self.min_mb, c)
+            .into_iter()
+            .max_by_key(|r| r.mbs);
+        let run = match best {
+            Some(r) => r,
+            None => {
+                unmap(buf, len);
+                return Err(AllocError::NotContiguous { offset: 0 });
+            }
+        };
+
+        // give back everything around the run, the allocation owns the run itself
+        let start = run.start_page * PAGE_SIZE;
+        let end = start + (run.mbs << 20);
+        if start > 0 {
+            unmap(buf, start);
+        }
+        if end < len {
+            unmap(buf.wrapping_add(end), len - end);
+        }
+        let run_buf = buf.wrapping_add(start);
+        let contiguity = Contiguity::Aliasing { mbs: run.mbs };
+        Ok(Allocation {
+            mem: unsafe { MemMap::new(run_buf, end - start, &*contiguity.resolver(run_buf), c) },
+            contiguity,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_match_probe_page_offset() {
+        let buf = 0x10_0000 as *mut u8;
+        let probe = 0x7f00_1234 as *const u8;
+        assert_eq!(store_addr(buf, 0, probe) as usize, 0x10_0234);
+        assert_eq!(store_addr(buf, 3, probe) as usize, 0x10_3234);
+        assert_eq!(store_addr(buf, 1, 0x2000 as *const u8) as usize, 0x10_1000);
+    }
+
+    #[test]
+    fn runs_from_synthetic_trace() {
+        // aliasing every 1 MB from page 100 for 3 MB, then a lone peak
+        let mut trace: Vec<u64> = (0..1200).map(|p| if p < WINDOW { 0 } else { 100 + p as u64 % 3 }).collect();
+        for &p in &[100, 100 + PAGES_PER_MB, 100 + 2 * PAGES_PER_MB, 100 + 3 * PAGES_PER_MB, 1150] {
+            trace[p] = 400;
+        }
+        // smeared onto a neighbour, still one peak
+        trace[101] = 300;
+
+        let peaks = find_peaks(&trace);
+        assert_eq!(peaks, vec![100, 356, 612, 868, 1150]);
+        assert_eq!(contiguous_runs(&peaks, 2), vec![(100, 3)]);
+        assert!(contiguous_runs(&peaks, 4).is_empty());
+    }
+}
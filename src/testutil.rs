@@ -0,0 +1,20 @@
+use crate::config::Config;
+use crate::hammer::{HammerAmount, HammerKernel};
+use crate::intelivy::IntelIvy;
+
+// single channel, single DIMM, dual rank IntelIvy with 4 KB of contiguous DRAM addresses
+pub(crate) fn config(refresh_period_us: usize) -> Config {
+    Config {
+        reads_per_hammer: 0,
+        amount: HammerAmount::Reads(0),
+        kernel: HammerKernel::Clflush,
+        contiguous_dram_addr: 1 << 12,
+        arch: Box::new(IntelIvy {
+            dual_channel: false,
+            dual_dimm: false,
+            dual_rank: true,
+        }),
+        refresh_period_us: Some(refresh_period_us),
+        ref_sync: None,
+    }
+}
@@ -0,0 +1,138 @@
+use std::fs;
+use std::ptr;
+use std::thread;
+use std::time::Duration;
+
+use nix::libc;
+
+use crate::alloc::{checked, huge_page_contiguity, map_aligned, unmap, AllocError, Allocation, AllocationStrategy};
+use crate::config::Config;
+use crate::timing::timer;
+
+const THP_BITS: usize = 21;
+const THP_SIZE: usize = 1 << THP_BITS;
+// synchronous collapse, Linux 6.1+
+const MADV_COLLAPSE: i32 = 25;
+const THP_ENABLED: &str = "/sys/kernel/mm/transparent_hugepage/enabled";
+const POLL_MS: u64 = 100;
+
+// transparent huge page from an aligned anonymous mapping
+pub(crate) struct Thp {
+    // how long to wait for khugepaged if the page was not backed by a huge page right away
+    pub timeout_ms: u64,
+}
+
+impl Default for Thp {
+    fn default() -> Self {
+        Thp { timeout_ms: 2000 }
+    }
+}
+
+// the selected mode in the sysfs file, e.g. "always [madvise] never" -> "madvise"
+pub(crate) fn thp_mode(enabled: &str) -> Option<&str> {
+    let start = enabled.find('[')?;
+    let end = enabled[start..].find(']')?;
+    Some(&enabled[start + 1..start + end])
+}
+
+// AnonHugePages in kB of the mapping containing addr
+pub(crate) fn anon_huge_kb(smaps: &str, addr: usize) -> Option<usize> {
+    let mut in_mapping = false;
+    for line in smaps.lines() {
+        let first = line.split_whitespace().next().unwrap_or("");
+        if let Some(dash) = first.find('-') {
+            // mapping header: "start-end perms offset dev inode path"
+            let start = usize::from_str_radix(&first[..dash], 16);
+            let end = usize::from_str_radix(&first[dash + 1..], 16);
+            if let (Ok(start), Ok(end)) = (start, end) {
+                in_mapping = start <= addr && addr < end;
+                continue;
+            }
+        }
+        if in_mapping && first == "AnonHugePages:" {
+            return line.split_whitespace().nth(1)?.parse().ok();
+        }
+    }
+    None
+}
+
+fn backed_by_thp(buf: *const u8) -> bool {
+    fs::read_to_string("/proc/self/smaps")
+        .ok()
+        .and_then(|s| anon_huge_kb(&s, buf as usize))
+        .is_some_and(|kb| kb << 10 >= THP_SIZE)
+}
+
+impl AllocationStrategy for Thp {
+    fn name(&self) -> &'static str {
+        "transparent huge page"
+    }
+
+    fn allocate(&self, c: &Config) -> Result<Allocation, AllocError> {
+        if let Ok(enabled) = fs::read_to_string(THP_ENABLED) {
+            if thp_mode(&enabled) == Some("never") {
+                return Err(AllocError::NoHugePage);
+            }
+        }
+
+        let buf = map_aligned(THP_SIZE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS)?;
+        unsafe {
+            libc::madvise(buf as *mut _, THP_SIZE, libc::MADV_HUGEPAGE);
+            ptr::write_bytes(buf, 0, THP_SIZE);
+        }
+
+        // the fault handler usually hands out a huge page right away, otherwise ask for
+        // a collapse and give khugepaged some time
+        if !backed_by_thp(buf) {
+            unsafe {
+                libc::madvise(buf as *mut _, THP_SIZE, MADV_COLLAPSE);
+            }
+            let t = timer();
+            let t0 = t.now();
+            while !backed_by_thp(buf) && t.elapsed_us(t0) < self.timeout_ms as u128 * 1000 {
+                thread::sleep(Duration::from_millis(POLL_MS));
+            }
+        }
+
+        if !backed_by_thp(buf) {
+            unmap(buf, THP_SIZE);
+            return Err(AllocError::NoHugePage);
+        }
+
+        // pagemap adds the physical address if we are allowed to see it, a THP that is not
+        // contiguous after all is rejected
+        checked(buf, THP_SIZE, huge_page_contiguity(buf, THP_SIZE, THP_BITS), c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SMAPS: &str = include_str!("../fixtures/proc/smaps");
+
+    #[test]
+    fn selected_thp_mode() {
+        assert_eq!(thp_mode("always [madvise] never\n"), Some("madvise"));
+        assert_eq!(thp_mode("[always] madvise never\n"), Some("always"));
+        assert_eq!(thp_mode("always madvise [never]\n"), Some("never"));
+        assert_eq!(thp_mode(""), None);
+        assert_eq!(thp_mode("always madvise never"), None);
+    }
+
+    #[test]
+    fn anon_huge_pages_of_the_containing_mapping() {
+        assert_eq!(anon_huge_kb(SMAPS, 0x7f2a_4000_0000), Some(2048));
+        assert_eq!(anon_huge_kb(SMAPS, 0x7f2a_401f_ffff), Some(2048));
+        assert_eq!(anon_huge_kb(SMAPS, 0x7f2a_4020_0000), Some(0));
+        assert_eq!(anon_huge_kb(SMAPS, 0x55d4_c3a0_1000), Some(0));
+    }
+
+    #[test]
+    fn mappings_without_anon_huge_pages() {
+        // the vdso block has no AnonHugePages line, addr is in no mapping at all
+        assert_eq!(anon_huge_kb(SMAPS, 0x7ffd_1c3e_1000), None);
+        assert_eq!(anon_huge_kb(SMAPS, 0x1000), None);
+        assert_eq!(anon_huge_kb("", 0x1000), None);
+    }
+}
@@ -0,0 +1,187 @@
+use std::arch::x86_64::{__cpuid, __rdtscp, _mm_lfence, _rdtsc};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+const CALIBRATION_MS: u128 = 20;
+const OVERHEAD_SAMPLES: usize = 1000;
+
+// how timestamps are kept from being reordered with the measured code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Serialization {
+    // rdtscp waits for preceding loads, lfence keeps later instructions from starting early
+    RdtscpLfence,
+    // cpuid fully serializes, but is slow and noisy in VMs
+    Cpuid,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Timer {
+    pub method: Serialization,
+    // TSC ticks at a constant rate independent of frequency scaling and C-states
+    pub invariant_tsc: bool,
+    pub ticks_per_us: f64,
+    // ticks measured between two back-to-back timestamps
+    pub overhead: u64,
+}
+
+// cpuid is only unsafe on older toolchains
+#[allow(unused_unsafe)]
+fn cpuid_bit(leaf: u32, edx_bit: u32) -> bool {
+    unsafe {
+        if __cpuid(0x8000_0000).eax < leaf {
+            return false;
+        }
+        __cpuid(leaf).edx & (1 << edx_bit) != 0
+    }
+}
+
+// CPUID.80000007H:EDX[8]
+pub(crate) fn has_invariant_tsc() -> bool {
+    cpuid_bit(0x8000_0007, 8)
+}
+
+// CPUID.80000001H:EDX[27]
+pub(crate) fn has_rdtscp() -> bool {
+    cpuid_bit(0x8000_0001, 27)
+}
+
+fn rdtsc_cpuid() -> u64 {
+    unsafe {
+        __cpuid(0);
+        _rdtsc()
+    }
+}
+
+fn rdtscp_lfence() -> u64 {
+    unsafe {
+        let mut aux = 0;
+        let t = __rdtscp(&mut aux);
+        _mm_lfence();
+        t
+    }
+}
+
+// prefers rdtscp if the CPU has it, cpuid works everywhere
+fn select_method(rdtscp: bool) -> Serialization {
+    if rdtscp {
+        Serialization::RdtscpLfence
+    } else {
+        Serialization::Cpuid
+    }
+}
+
+impl Timer {
+    pub(crate) fn new(method: Serialization) -> Self {
+        let mut t = Timer {
+            method,
+            invariant_tsc: has_invariant_tsc(),
+            ticks_per_us: 0.0,
+            overhead: 0,
+        };
+        t.ticks_per_us = t.measure_frequency();
+        t.overhead = t.measure_overhead();
+        t
+    }
+
+    pub(crate) fn detect() -> Self {
+        Timer::new(select_method(has_rdtscp()))
+    }
+
+    #[inline(always)]
+    pub(crate) fn now(&self) -> u64 {
+        match self.method {
+            Serialization::RdtscpLfence => rdtscp_lfence(),
+            Serialization::Cpuid => rdtsc_cpuid(),
+        }
+    }
+
+    // TSC ticks per microsecond, measured against the monotonic clock
+    fn measure_frequency(&self) -> f64 {
+        let t0 = Instant::now();
+        let c0 = self.now();
+        while t0.elapsed().as_millis() < CALIBRATION_MS {}
+        let c1 = self.now();
+        (c1 - c0) as f64 / t0.elapsed().as_micros() as f64
+    }
+
+    fn measure_overhead(&self) -> u64 {
+        let mut samples: Vec<u64> = (0..OVERHEAD_SAMPLES)
+            .map(|_| {
+                let t0 = self.now();
+                self.now() - t0
+            })
+            .collect();
+        samples.sort();
+        samples[samples.len() / 2]
+    }
+
+    pub(crate) fn ticks_to_ns(&self, ticks: u64) -> f64 {
+        ticks as f64 * 1000.0 / self.ticks_per_us
+    }
+
+    pub(crate) fn ns_to_ticks(&self, ns: f64) -> u64 {
+        (ns * self.ticks_per_us / 1000.0) as u64
+    }
+
+    // ticks since start, without the timer overhead
+    pub(crate) fn elapsed(&self, start: u64) -> u64 {
+        self.now().saturating_sub(start).saturating_sub(self.overhead)
+    }
+
+    pub(crate) fn elapsed_ns(&self, start: u64) -> u128 {
+        self.ticks_to_ns(self.elapsed(start)) as u128
+    }
+
+    pub(crate) fn elapsed_us(&self, start: u64) -> u128 {
+        self.elapsed_ns(start) / 1000
+    }
+}
+
+static TIMER: OnceLock<Timer> = OnceLock::new();
+
+// the process-wide timer, detected and calibrated on first use
+pub(crate) fn timer() -> &'static Timer {
+    TIMER.get_or_init(|| {
+        let t = Timer::detect();
+        if !t.invariant_tsc {
+            println!("Warning: TSC is not invariant, timings depend on the CPU frequency");
+        }
+        t
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falls_back_to_cpuid_without_rdtscp() {
+        assert_eq!(select_method(true), Serialization::RdtscpLfence);
+        assert_eq!(select_method(false), Serialization::Cpuid);
+    }
+
+    #[test]
+    fn both_methods_count_up() {
+        for &method in &[Serialization::RdtscpLfence, Serialization::Cpuid] {
+            if method == Serialization::RdtscpLfence && !has_rdtscp() {
+                continue;
+            }
+            let t = Timer::new(method);
+            assert!(t.ticks_per_us > 0.0, "{:?}", method);
+            let t0 = t.now();
+            assert!(t.now() >= t0, "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn converts_between_ticks_and_time() {
+        let t = Timer { method: Serialization::Cpuid, invariant_tsc: true, ticks_per_us: 3000.0, overhead: 0 };
+        assert_eq!(t.ticks_to_ns(3000), 1000.0);
+        assert_eq!(t.ns_to_ticks(1000.0), 3000);
+    }
+
+    #[test]
+    fn timer_is_detected_once() {
+        assert!(std::ptr::eq(timer(), timer()));
+    }
+}